@@ -0,0 +1,296 @@
+//! JSON 接口，使用 `Authorization: Bearer <api_token>` 认证
+//!
+//! - `GET {api_path}/entries`：列出当前白名单
+//! - `POST {api_path}/entries`：添加 IP 或 CIDR，`{"address": "1.2.3.0/24", "ttl": 3600}`
+//! - `DELETE {api_path}/entries/{address}`：删除
+//! - `PATCH {api_path}/entries/{address}`：把剩余时间延长到 `ttl`，`{"ttl": 3600}`
+//! - `POST {api_path}/logout`：注销，删除包含调用者 IP 的项，包括通过接口添加的网段
+//! - `POST {api_path}/renew`：主动续期包含调用者 IP 的项，需要开启滑动续期
+//! - `GET {api_path}/status`：调用者 IP 的状态，同状态页面的 json
+
+use crate::{auth::User, MyAppData, Result};
+use bytes::Buf;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Serialize)]
+struct EntryJson {
+    address: IpAddr,
+    cidr: String,
+    owner: String,
+    /// unix 时间戳（秒）
+    created: u64,
+    expires: u64,
+    /// 剩余秒数
+    ttl: u64,
+}
+
+impl From<crate::service::EntryInfo> for EntryJson {
+    fn from(e: crate::service::EntryInfo) -> Self {
+        EntryJson {
+            address: e.address,
+            cidr: e.cidr,
+            owner: e.owner,
+            created: unix_secs(e.created),
+            expires: unix_secs(e.expire),
+            ttl: e
+                .expire
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddBody {
+    address: String,
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ExtendBody {
+    ttl: Option<u64>,
+}
+
+pub(crate) async fn handle(
+    ctx: Arc<MyAppData>,
+    req: Request<Body>,
+    ip: IpAddr,
+) -> Result<Response<Body>> {
+    let token = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim();
+    let user = ctx.find_user(|users| users.authenticate_api(token));
+    let Some(user) = user else {
+        warn!("接口未授权访问：{}", ip);
+        if let Some(lockout) = &ctx.lockout {
+            lockout.failure(&ip);
+        }
+        let mut resp = error(StatusCode::UNAUTHORIZED, "未授权")?;
+        resp.headers_mut().insert(
+            hyper::header::WWW_AUTHENTICATE,
+            hyper::header::HeaderValue::from_static("Bearer"),
+        );
+        return Ok(resp);
+    };
+    if let Some(lockout) = &ctx.lockout {
+        lockout.success(&ip);
+    }
+
+    let route = req.uri().path()[ctx.api_path.len()..]
+        .trim_matches('/')
+        .to_string();
+    let target = route.strip_prefix("entries/").map(|addr| {
+        percent_encoding::percent_decode_str(addr)
+            .decode_utf8_lossy()
+            .into_owned()
+    });
+    match (req.method().clone(), route.as_str(), target) {
+        (Method::GET, "entries", _) => list(ctx).await,
+        (Method::POST, "entries", _) => add(ctx, req, &user).await,
+        (Method::DELETE, _, Some(target)) => remove(ctx, &target, &user).await,
+        (Method::PATCH, _, Some(target)) => extend(ctx, req, &target, &user).await,
+        (Method::POST, "logout", _) => logout(ctx, ip, &user).await,
+        (Method::POST, "renew", _) => renew(ctx, ip, &user).await,
+        (Method::GET, "status", _) => crate::status::json(&ctx, ip).await,
+        (_, "entries" | "logout" | "renew" | "status", _) | (_, _, Some(_)) => {
+            error(StatusCode::METHOD_NOT_ALLOWED, "不支持的请求方法")
+        }
+        _ => error(StatusCode::NOT_FOUND, "接口不存在"),
+    }
+}
+
+async fn list(ctx: Arc<MyAppData>) -> Result<Response<Body>> {
+    let entries = ctx.service.lock().unwrap().list();
+    let entries: Vec<EntryJson> = entries.await.into_iter().map(EntryJson::from).collect();
+    json(StatusCode::OK, &serde_json::json!({ "entries": entries }))
+}
+
+async fn add(ctx: Arc<MyAppData>, mut req: Request<Body>, user: &User) -> Result<Response<Body>> {
+    let body = hyper::body::aggregate(req.body_mut()).await?;
+    let body: AddBody = match serde_json::from_reader(body.reader()) {
+        Ok(body) => body,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let (addr, prefixlen) = if let Ok(net) = body.address.parse::<IpNet>() {
+        let Some(net) = canonical_net(net) else {
+            return error(StatusCode::BAD_REQUEST, "address 无效");
+        };
+        (net.addr(), Some(net.prefix_len()))
+    } else if let Ok(ip) = body.address.parse::<IpAddr>() {
+        (ip, None)
+    } else {
+        return error(StatusCode::BAD_REQUEST, "address 无效");
+    };
+    let Some(ttl) = parse_ttl(body.ttl) else {
+        return error(StatusCode::BAD_REQUEST, "ttl 无效");
+    };
+    let ttl = user.clamp_ttl(ttl.unwrap_or(ctx.timeout));
+    let entry = ctx
+        .service
+        .lock()
+        .unwrap()
+        .add(addr, prefixlen, Some(ttl), &user.name);
+    let Some(entry) = entry.await else {
+        return error(StatusCode::BAD_REQUEST, "address 无效");
+    };
+    info!("用户 {} 通过接口添加了 {}", user.name, entry.cidr);
+    json(StatusCode::CREATED, &EntryJson::from(entry))
+}
+
+async fn remove(ctx: Arc<MyAppData>, target: &str, user: &User) -> Result<Response<Body>> {
+    let Some(net) = parse_net(target) else {
+        return error(StatusCode::BAD_REQUEST, "address 无效");
+    };
+    let count = ctx.service.lock().unwrap().remove(net);
+    if count.await == 0 {
+        return error(StatusCode::NOT_FOUND, "不在白名单中");
+    }
+    info!("用户 {} 通过接口删除了 {}", user.name, target);
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?)
+}
+
+async fn extend(
+    ctx: Arc<MyAppData>,
+    mut req: Request<Body>,
+    target: &str,
+    user: &User,
+) -> Result<Response<Body>> {
+    let Some(net) = parse_net(target) else {
+        return error(StatusCode::BAD_REQUEST, "address 无效");
+    };
+    let body = hyper::body::aggregate(req.body_mut()).await?;
+    let body: ExtendBody = if body.has_remaining() {
+        match serde_json::from_reader(body.reader()) {
+            Ok(body) => body,
+            Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+        }
+    } else {
+        ExtendBody { ttl: None }
+    };
+    let Some(ttl) = parse_ttl(body.ttl) else {
+        return error(StatusCode::BAD_REQUEST, "ttl 无效");
+    };
+    let ttl = user.clamp_ttl(ttl.unwrap_or(ctx.timeout));
+    let entries = ctx.service.lock().unwrap().extend(net, Some(ttl));
+    let entries: Vec<EntryJson> = entries.await.into_iter().map(EntryJson::from).collect();
+    if entries.is_empty() {
+        return error(StatusCode::NOT_FOUND, "不在白名单中");
+    }
+    info!("用户 {} 通过接口延长了 {}", user.name, target);
+    json(StatusCode::OK, &serde_json::json!({ "entries": entries }))
+}
+
+async fn logout(ctx: Arc<MyAppData>, ip: IpAddr, user: &User) -> Result<Response<Body>> {
+    let entries = ctx.service.lock().unwrap().logout(ip, true);
+    let entries: Vec<EntryJson> = entries.await.into_iter().map(EntryJson::from).collect();
+    if entries.is_empty() {
+        return error(StatusCode::NOT_FOUND, "不在白名单中");
+    }
+    info!("用户 {} 通过接口注销了 {}", user.name, ip);
+    json(StatusCode::OK, &serde_json::json!({ "entries": entries }))
+}
+
+async fn renew(ctx: Arc<MyAppData>, ip: IpAddr, user: &User) -> Result<Response<Body>> {
+    let entries = ctx.service.lock().unwrap().renew(ip);
+    let entries: Vec<EntryJson> = entries.await.into_iter().map(EntryJson::from).collect();
+    if entries.is_empty() {
+        return error(StatusCode::NOT_FOUND, "不在白名单中或没有开启滑动续期");
+    }
+    info!("用户 {} 通过接口续期了 {}", user.name, ip);
+    json(StatusCode::OK, &serde_json::json!({ "entries": entries }))
+}
+
+/// 未指定时为 `Some(None)`，即使用默认的 `timeout`，为 0 时无效
+fn parse_ttl(ttl: Option<u64>) -> Option<Option<Duration>> {
+    match ttl {
+        None => Some(None),
+        Some(0) => None,
+        Some(ttl) => Some(Some(Duration::from_secs(ttl))),
+    }
+}
+
+fn parse_net(addr: &str) -> Option<IpNet> {
+    match addr.parse::<IpNet>() {
+        Ok(net) => canonical_net(net),
+        Err(_) => addr
+            .parse::<IpAddr>()
+            .map(|ip| IpNet::from(ip.to_canonical()))
+            .ok(),
+    }
+}
+
+/// 把 IPv4 映射的 IPv6 网段（如 `::ffff:1.2.3.0/120`）转换为 IPv4 网段（`1.2.3.0/24`），
+/// 前缀不足 96 位的无法转换，返回 `None`
+fn canonical_net(net: IpNet) -> Option<IpNet> {
+    let net = match (net, net.addr().to_canonical()) {
+        (IpNet::V6(v6), IpAddr::V4(v4)) => {
+            IpNet::new(IpAddr::V4(v4), v6.prefix_len().checked_sub(96)?).ok()?
+        }
+        _ => net,
+    };
+    Some(net.trunc())
+}
+
+/// unix 时间戳（秒）
+pub(crate) fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value)?.into())?)
+}
+
+fn error(status: StatusCode, message: &str) -> Result<Response<Body>> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_net_maps_ipv4_mapped_networks() {
+        let net = "::ffff:1.2.3.0/120".parse().unwrap();
+        assert_eq!(canonical_net(net), Some("1.2.3.0/24".parse().unwrap()));
+        let net = "::ffff:1.2.3.4/128".parse().unwrap();
+        assert_eq!(canonical_net(net), Some("1.2.3.4/32".parse().unwrap()));
+        // 前缀不足 96 位时不是一个 IPv4 网段
+        let net = "::ffff:1.2.3.0/80".parse().unwrap();
+        assert_eq!(canonical_net(net), None);
+    }
+
+    #[test]
+    fn canonical_net_truncates_host_bits() {
+        let net = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(canonical_net(net), Some("10.1.0.0/16".parse().unwrap()));
+        let net = "2001:db8::1/64".parse().unwrap();
+        assert_eq!(canonical_net(net), Some("2001:db8::/64".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_net_accepts_plain_and_mapped_addresses() {
+        assert_eq!(parse_net("1.2.3.4"), Some("1.2.3.4/32".parse().unwrap()));
+        assert_eq!(
+            parse_net("::ffff:1.2.3.4"),
+            Some("1.2.3.4/32".parse().unwrap())
+        );
+        assert_eq!(parse_net("x"), None);
+    }
+}
@@ -0,0 +1,316 @@
+use crate::{config::WhiteListConfig, totp, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::{
+    collections::HashMap,
+    io::{BufRead, IsTerminal},
+    sync::Mutex,
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
+
+/// 旧版配置中 `whitelist.token` 对应的用户名
+const DEFAULT_USER: &str = "default";
+
+#[derive(Clone)]
+pub(crate) struct User {
+    pub name: String,
+    pub token: String,
+    pub token_hash: String,
+    pub totp_secret: String,
+    pub cert_names: Vec<String>,
+    pub api_token: String,
+    pub api_token_hash: String,
+    /// 一次提交最多保留多久
+    pub max_ttl: Duration,
+    pub enabled: bool,
+}
+
+impl User {
+    /// 把请求的时长限制在 `max_ttl` 以内
+    pub fn clamp_ttl(&self, ttl: Duration) -> Duration {
+        ttl.min(self.max_ttl)
+    }
+
+    /// 校验 token，优先使用 `token_hash`，明文比较也是常量时间的
+    fn verify_token(&self, token: &str) -> bool {
+        self.verify_secret("token_hash", &self.token, &self.token_hash, token)
+    }
+
+    /// 校验 JSON 接口的 token，规则同 `verify_token`
+    fn verify_api_token(&self, token: &str) -> bool {
+        self.verify_secret(
+            "api_token_hash",
+            &self.api_token,
+            &self.api_token_hash,
+            token,
+        )
+    }
+
+    fn verify_secret(&self, hash_name: &str, plain: &str, hash: &str, token: &str) -> bool {
+        if !hash.is_empty() {
+            match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(token.as_bytes(), &hash)
+                    .is_ok(),
+                Err(err) => {
+                    error!("用户 {} 的 {} 无效: {}", self.name, hash_name, err);
+                    false
+                }
+            }
+        } else if !plain.is_empty() {
+            plain.as_bytes().ct_eq(token.as_bytes()).into()
+        } else {
+            false
+        }
+    }
+}
+
+pub(crate) struct Users {
+    list: Vec<User>,
+    totp_skew: u8,
+    /// 每个用户最后一次使用的 TOTP 计数器，用于防止重放
+    totp_used: Mutex<HashMap<String, u64>>,
+}
+
+impl Users {
+    pub fn from_config(cfg: &WhiteListConfig) -> Self {
+        let timeout = Duration::from_secs(cfg.timeout.into());
+        let mut list = vec![];
+        if !cfg.token.is_empty() {
+            list.push(User {
+                name: DEFAULT_USER.into(),
+                token: cfg.token.clone(),
+                token_hash: String::new(),
+                totp_secret: String::new(),
+                cert_names: vec![],
+                api_token: String::new(),
+                api_token_hash: String::new(),
+                max_ttl: timeout,
+                enabled: true,
+            });
+        }
+        for u in &cfg.users {
+            if list.iter().any(|i| i.name == u.name) {
+                warn!("用户名重复: {}", u.name);
+                continue;
+            }
+            if u.token.is_empty()
+                && u.token_hash.is_empty()
+                && u.cert_names.is_empty()
+                && u.api_token.is_empty()
+                && u.api_token_hash.is_empty()
+            {
+                warn!("用户 {} 没有设置任何登录方式", u.name);
+            }
+            if !u.token_hash.is_empty() {
+                if let Err(err) = PasswordHash::new(&u.token_hash) {
+                    warn!("用户 {} 的 token_hash 无效: {}", u.name, err);
+                }
+            }
+            if !u.api_token_hash.is_empty() {
+                if let Err(err) = PasswordHash::new(&u.api_token_hash) {
+                    warn!("用户 {} 的 api_token_hash 无效: {}", u.name, err);
+                }
+            }
+            if !u.totp_secret.is_empty() && totp::decode_secret(&u.totp_secret).is_none() {
+                warn!("用户 {} 的 totp_secret 无效", u.name);
+            }
+            list.push(User {
+                name: u.name.clone(),
+                token: u.token.clone(),
+                token_hash: u.token_hash.clone(),
+                totp_secret: u.totp_secret.clone(),
+                cert_names: u.cert_names.clone(),
+                api_token: u.api_token.clone(),
+                api_token_hash: u.api_token_hash.clone(),
+                max_ttl: match u.max_ttl {
+                    0 => timeout,
+                    max_ttl => Duration::from_secs(max_ttl.into()),
+                },
+                enabled: u.enabled,
+            });
+        }
+        if !list.iter().any(|u| u.enabled) {
+            warn!("没有可用的用户");
+        }
+        Users {
+            list,
+            totp_skew: cfg.totp_skew,
+            totp_used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 替换为新加载的用户列表，保留 TOTP 防重放记录
+    pub fn reload(&mut self, users: Users) {
+        self.list = users.list;
+        self.totp_skew = users.totp_skew;
+    }
+
+    /// 根据 token 查找已启用的用户，设置了 TOTP 的用户还需要校验验证码
+    pub fn authenticate(&self, token: &str, code: &str) -> Option<&User> {
+        if token.is_empty() {
+            return None;
+        }
+        let user = self
+            .list
+            .iter()
+            .find(|u| u.enabled && u.verify_token(token))?;
+        if !user.totp_secret.is_empty() {
+            let key = totp::decode_secret(&user.totp_secret)?;
+            let Some(counter) = totp::verify(&key, code, totp::counter_now(), self.totp_skew)
+            else {
+                debug!("用户 {} 的验证码错误", user.name);
+                return None;
+            };
+            let mut used = self.totp_used.lock().unwrap();
+            if used.get(&user.name).is_some_and(|c| counter <= *c) {
+                warn!("用户 {} 的验证码已被使用过", user.name);
+                return None;
+            }
+            used.insert(user.name.clone(), counter);
+        }
+        Some(user)
+    }
+
+    /// 根据 `api_token`（或 `api_token_hash`）查找已启用的用户，用于 JSON 接口
+    pub fn authenticate_api(&self, token: &str) -> Option<&User> {
+        if token.is_empty() {
+            return None;
+        }
+        self.list
+            .iter()
+            .find(|u| u.enabled && u.verify_api_token(token))
+    }
+
+    /// 根据客户端证书中的名称查找已启用的用户，不区分大小写
+    pub fn find_by_cert(&self, names: &[String]) -> Option<&User> {
+        self.list.iter().find(|u| {
+            u.enabled
+                && u.cert_names
+                    .iter()
+                    .any(|c| names.iter().any(|n| n.eq_ignore_ascii_case(c)))
+        })
+    }
+
+    /// 已启用的用户名
+    pub fn enabled_names(&self) -> Vec<String> {
+        self.list
+            .iter()
+            .filter(|u| u.enabled)
+            .map(|u| u.name.clone())
+            .collect()
+    }
+}
+
+/// 命令行 `hash`：从标准输入读取 token，输出可用于 `token_hash` 的 argon2 哈希
+pub(crate) fn hash_command() -> Result<()> {
+    let stdin = std::io::stdin();
+    let mut token = String::new();
+    if stdin.is_terminal() {
+        // 在终端中输入时关闭回显，token 不会显示在屏幕上
+        token = rpassword::prompt_password("请输入 token: ")?;
+    } else {
+        stdin.lock().read_line(&mut token)?;
+    }
+    let token = token.trim_end_matches(['\r', '\n']);
+    if token.is_empty() {
+        return Err("token 不能为空".into());
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(token.as_bytes(), &salt)
+        .map_err(|err| err.to_string())?;
+    println!("{}", hash);
+    Ok(())
+}
+
+/// 命令行 `totp <用户名>`：生成 TOTP 密钥，输出密钥和供验证器应用使用的 otpauth URI
+pub(crate) fn totp_command(user: Option<String>) -> Result<()> {
+    let Some(user) = user else {
+        return Err("用法: totp <用户名>".into());
+    };
+    let secret = totp::new_secret();
+    println!("totp_secret: {}", secret);
+    println!("{}", totp::uri(crate::APP_NAME, &user, &secret));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn users(users: serde_json::Value) -> Users {
+        let cfg: WhiteListConfig =
+            serde_json::from_value(serde_json::json!({ "users": users })).expect("配置无效");
+        Users::from_config(&cfg)
+    }
+
+    fn hash(token: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(token.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn api_token_plain_and_hash() {
+        let users = users(serde_json::json!([
+            { "name": "plain", "api_token": "aaa" },
+            { "name": "hashed", "api_token_hash": hash("bbb") },
+            { "name": "disabled", "api_token": "ccc", "enabled": false },
+        ]));
+        let name = |token| users.authenticate_api(token).map(|u| u.name.as_str());
+        assert_eq!(name("aaa"), Some("plain"));
+        assert_eq!(name("bbb"), Some("hashed"));
+        assert_eq!(name("ccc"), None);
+        assert_eq!(name(""), None);
+        assert_eq!(name("aa"), None);
+    }
+
+    #[test]
+    fn totp_skew_and_replay() {
+        // 离下一个步长太近时先等待，避免测试过程中计数器变化
+        let secs_left = || {
+            30 - SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                % 30
+        };
+        while secs_left() < 3 {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let users = users(serde_json::json!([
+            { "name": "alice", "token": "aaa", "totp_secret": secret },
+        ]));
+        let key = totp::decode_secret(secret).unwrap();
+        let now = totp::counter_now();
+        let code = |counter| totp::hotp(&key, counter);
+
+        assert!(users.authenticate("aaa", "").is_none());
+        assert!(users.authenticate("bbb", &code(now)).is_none());
+        // 默认允许前后各一个步长
+        assert!(users.authenticate("aaa", &code(now - 2)).is_none());
+        assert!(users.authenticate("aaa", &code(now + 2)).is_none());
+        assert!(users.authenticate("aaa", &code(now - 1)).is_some());
+        // 同一个验证码不能再次使用
+        assert!(users.authenticate("aaa", &code(now - 1)).is_none());
+        assert!(users.authenticate("aaa", &code(now)).is_some());
+        // 比已使用的更早的验证码也不能使用
+        assert!(users.authenticate("aaa", &code(now - 1)).is_none());
+        assert!(users.authenticate("aaa", &code(now)).is_none());
+        assert!(users.authenticate("aaa", &code(now + 1)).is_some());
+    }
+
+    #[test]
+    fn api_token_does_not_log_in_form() {
+        let users = users(serde_json::json!([{ "name": "bot", "api_token": "aaa" }]));
+        assert!(users.authenticate("aaa", "").is_none());
+    }
+}
@@ -52,25 +52,116 @@ pub(crate) struct Config {
 
     #[serde(default)]
     pub baidu_location: BaiduLocationConfig,
+
+    #[serde(default)]
+    pub backends: Vec<BackendConfig>,
+
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct ListenConfig {
-    pub urls: Vec<String>,
+    pub urls: Vec<ListenUrl>,
     pub path: String,
 
     #[serde(default = "default_allow_proxy")]
     pub allow_proxy: bool,
+
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<String>,
+
+    #[serde(default)]
+    pub auth_path: String,
+
+    #[serde(default)]
+    pub api_path: String,
 }
 
 fn default_allow_proxy() -> bool {
     true
 }
 
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.0/8".into(), "::1".into()]
+}
+
+/// 监听地址，可以直接写地址，也可以写成对象以附加证书等配置
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ListenUrl {
+    Url(String),
+    Config(ListenUrlConfig),
+}
+
+impl ListenUrl {
+    pub fn into_config(self) -> ListenUrlConfig {
+        match self {
+            ListenUrl::Url(url) => ListenUrlConfig {
+                url,
+                ..Default::default()
+            },
+            ListenUrl::Config(config) => config,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct ListenUrlConfig {
+    pub url: String,
+
+    #[serde(default)]
+    pub cert: String,
+
+    #[serde(default)]
+    pub key: String,
+
+    #[serde(default)]
+    pub client_ca: String,
+
+    #[serde(default)]
+    pub client_cert_get: bool,
+
+    #[serde(default)]
+    pub mode: String,
+
+    #[serde(default)]
+    pub owner: String,
+
+    #[serde(default)]
+    pub source: AddressSource,
+}
+
+/// 经过代理时，从哪里获取客户端地址
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AddressSource {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+    XRealIp,
+    ProxyProtocol,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct WhiteListConfig {
+    #[serde(default)]
     pub token: String,
+
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    #[serde(default = "default_totp_skew")]
+    pub totp_skew: u8,
+
+    // 以下 4 项为旧版 nginx 配置，设置了 nginx_conf 时等同于在 backends 中添加一个 nginx 后端
+    #[serde(default)]
     pub nginx_conf: String,
+
+    #[serde(default)]
     pub nginx_exe: String,
 
     #[serde(default = "default_remote_addr_var")]
@@ -82,6 +173,9 @@ pub(crate) struct WhiteListConfig {
     #[serde(default = "default_timeout")]
     pub timeout: u32,
 
+    #[serde(default)]
+    pub sliding: bool,
+
     #[serde(default = "default_loop_delay")]
     pub loop_delay: u32,
 
@@ -93,6 +187,46 @@ pub(crate) struct WhiteListConfig {
 
     #[serde(default)]
     pub preset: Vec<String>,
+
+    #[serde(default)]
+    pub state_file: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserConfig {
+    pub name: String,
+
+    #[serde(default)]
+    pub token: String,
+
+    #[serde(default)]
+    pub token_hash: String,
+
+    #[serde(default)]
+    pub totp_secret: String,
+
+    #[serde(default)]
+    pub cert_names: Vec<String>,
+
+    #[serde(default)]
+    pub api_token: String,
+
+    #[serde(default)]
+    pub api_token_hash: String,
+
+    #[serde(default)]
+    pub max_ttl: u32,
+
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_totp_skew() -> u8 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 fn default_remote_addr_var() -> String {
@@ -103,6 +237,148 @@ fn default_result_var() -> String {
     "ip_whitelist".into()
 }
 
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum BackendConfig {
+    Nginx(NginxBackendConfig),
+    Nftables(NftablesBackendConfig),
+    Haproxy(HaproxyBackendConfig),
+    Caddy(CaddyBackendConfig),
+    Traefik(TraefikBackendConfig),
+    Apache(ApacheBackendConfig),
+    Template(TemplateBackendConfig),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NginxBackendConfig {
+    pub conf: String,
+    pub exe: String,
+
+    #[serde(default = "default_remote_addr_var")]
+    pub remote_addr_var: String,
+
+    #[serde(default = "default_result_var")]
+    pub result_var: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NftablesBackendConfig {
+    #[serde(default = "default_nft_exe")]
+    pub exe: String,
+
+    #[serde(default = "default_nft_family")]
+    pub family: String,
+
+    #[serde(default = "default_nft_table")]
+    pub table: String,
+
+    #[serde(default = "default_nft_set4")]
+    pub set4: String,
+
+    #[serde(default = "default_nft_set6")]
+    pub set6: String,
+}
+
+fn default_nft_exe() -> String {
+    "nft".into()
+}
+
+fn default_nft_family() -> String {
+    "inet".into()
+}
+
+fn default_nft_table() -> String {
+    "filter".into()
+}
+
+fn default_nft_set4() -> String {
+    "whitelist4".into()
+}
+
+fn default_nft_set6() -> String {
+    "whitelist6".into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HaproxyBackendConfig {
+    pub acl_file: String,
+
+    #[serde(default)]
+    pub socket: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CaddyBackendConfig {
+    #[serde(default = "default_caddy_admin")]
+    pub admin: String,
+
+    pub path: String,
+}
+
+fn default_caddy_admin() -> String {
+    "http://localhost:2019".into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TraefikBackendConfig {
+    pub path: String,
+
+    #[serde(default = "default_traefik_middleware")]
+    pub middleware: String,
+}
+
+fn default_traefik_middleware() -> String {
+    "whitelist".into()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApacheBackendConfig {
+    pub conf: String,
+
+    #[serde(default = "default_apache_exe")]
+    pub exe: String,
+
+    #[serde(default)]
+    pub cwd: String,
+
+    #[serde(default = "default_apache_test_args")]
+    pub test_args: Vec<String>,
+
+    #[serde(default = "default_apache_reload_args")]
+    pub reload_args: Vec<String>,
+}
+
+fn default_apache_exe() -> String {
+    "apachectl".into()
+}
+
+fn default_apache_test_args() -> Vec<String> {
+    vec!["configtest".into()]
+}
+
+fn default_apache_reload_args() -> Vec<String> {
+    vec!["graceful".into()]
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TemplateBackendConfig {
+    #[serde(default = "default_template_name")]
+    pub name: String,
+
+    pub template: String,
+    pub output: String,
+
+    #[serde(default)]
+    pub validate: Vec<Vec<String>>,
+
+    #[serde(default)]
+    pub reload: Vec<Vec<String>>,
+}
+
+fn default_template_name() -> String {
+    "template".into()
+}
+
 fn default_timeout() -> u32 {
     3600
 }
@@ -111,30 +387,82 @@ fn default_loop_delay() -> u32 {
     15
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub(crate) struct MessageConfig {
     pub bark: String,
 }
 
-impl Default for MessageConfig {
+#[derive(Deserialize, Default)]
+pub(crate) struct BaiduLocationConfig {
+    pub ak: String,
+    pub referrer: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct LockoutConfig {
+    pub max_failures: u32,
+    pub prefix_max_failures: u32,
+    pub ipv4_prefixlen: u8,
+    pub ipv6_prefixlen: u8,
+    pub window: u32,
+    pub ban_time: u32,
+    pub max_ban_time: u32,
+    pub state_file: String,
+}
+
+impl Default for LockoutConfig {
     fn default() -> Self {
-        MessageConfig {
-            bark: String::new(),
+        LockoutConfig {
+            max_failures: 5,
+            prefix_max_failures: 20,
+            ipv4_prefixlen: 24,
+            ipv6_prefixlen: 64,
+            window: 600,
+            ban_time: 60,
+            max_ban_time: 86400,
+            state_file: String::new(),
         }
     }
 }
 
+#[derive(Deserialize, Clone, Copy)]
+pub(crate) struct RateLimitRule {
+    #[serde(default)]
+    pub rate: f64,
+    /// 为 0 时取 `rate` 向上取整，即一秒内补充的请求数
+    #[serde(default)]
+    pub burst: u32,
+}
+
 #[derive(Deserialize)]
-pub(crate) struct BaiduLocationConfig {
-    pub ak: String,
-    pub referrer: String,
+#[serde(default)]
+pub(crate) struct RateLimitConfig {
+    pub get: RateLimitRule,
+    pub post: RateLimitRule,
+    pub api: RateLimitRule,
+    pub global: RateLimitRule,
 }
 
-impl Default for BaiduLocationConfig {
+impl Default for RateLimitConfig {
     fn default() -> Self {
-        BaiduLocationConfig {
-            ak: String::new(),
-            referrer: String::new(),
+        RateLimitConfig {
+            get: RateLimitRule {
+                rate: 1.0,
+                burst: 20,
+            },
+            post: RateLimitRule {
+                rate: 0.2,
+                burst: 5,
+            },
+            api: RateLimitRule {
+                rate: 0.0,
+                burst: 0,
+            },
+            global: RateLimitRule {
+                rate: 0.0,
+                burst: 0,
+            },
         }
     }
 }
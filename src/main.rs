@@ -1,17 +1,28 @@
 #[macro_use]
 extern crate log;
 
+mod api;
+mod auth;
 mod config;
+mod proxyprotocol;
 mod service;
+mod status;
+mod systemd;
+mod tls;
+mod totp;
+#[cfg(unix)]
+mod unixsocket;
 
 use bytes::Buf;
-use futures_util::{future, FutureExt, TryFutureExt};
+use config::AddressSource;
+use futures_util::{future, FutureExt};
 use hyper::{Body, Method, Request, Response, StatusCode};
+use ipnet::IpNet;
 use service::*;
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
     time::Duration,
 };
 
@@ -20,15 +31,75 @@ type Result<T> = std::result::Result<T, Error>;
 
 struct MyAppData {
     service: Mutex<WhiteListService>,
-    token: String,
-    allow_proxy: bool,
+    users: RwLock<auth::Users>,
+    lockout: Option<LockoutService>,
+    ratelimit: Option<RateLimitService>,
+    /// 关闭代理支持时为空
+    trusted_proxies: Vec<IpNet>,
+    /// 未指定时长时使用的 `timeout`
+    timeout: Duration,
     path: String,
+    auth_path: String,
+    api_path: String,
+    tls: Vec<Arc<tls::TlsAcceptor>>,
+}
+
+impl MyAppData {
+    /// 在用户列表中查找用户，校验哈希比较耗时，避免阻塞其他请求
+    fn find_user(&self, f: impl FnOnce(&auth::Users) -> Option<&auth::User>) -> Option<auth::User> {
+        tokio::task::block_in_place(|| f(&self.users.read().unwrap()).cloned())
+    }
+}
+
+struct Listener {
+    incoming: Incoming,
+    tls: Option<Arc<tls::TlsAcceptor>>,
+    client_cert_get: bool,
+    source: AddressSource,
+}
+
+impl Listener {
+    fn conn_info(&self, remote_addr: SocketAddr, proxied: bool) -> ConnInfo {
+        ConnInfo {
+            remote_addr,
+            proxied,
+            cert_names: vec![],
+            client_cert_get: self.client_cert_get,
+            source: self.source,
+        }
+    }
+}
+
+enum Incoming {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// 同一连接上的所有请求共享的信息
+struct ConnInfo {
+    /// unix socket 连接为 `127.0.0.1:0`
+    remote_addr: SocketAddr,
+    /// 连接来自本机的反向代理，总是从 http 头中获取远程地址
+    proxied: bool,
+    /// 客户端证书中的名称，没有提供证书时为空
+    cert_names: Vec<String>,
+    client_cert_get: bool,
+    source: AddressSource,
 }
 
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("hash") => return auth::hash_command(),
+        Some("totp") => return auth::totp_command(args.next()),
+        Some(cmd) => return Err(format!("未知的命令: {}", cmd).into()),
+        None => {}
+    }
+
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", format!("info,{}=debug", APP_NAME));
     }
@@ -36,20 +107,32 @@ async fn main() -> Result<()> {
 
     let cfg = config::load_config()?;
 
+    let mut trusted_proxies = vec![];
     if cfg.listen.allow_proxy {
-        warn!("已开启代理支持，请注意防范远程地址伪造");
+        for i in &cfg.listen.trusted_proxies {
+            let net = i
+                .parse::<IpNet>()
+                .or_else(|_| i.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("trusted_proxies 中的地址无效: {}", i))?;
+            trusted_proxies.push(net);
+        }
+        info!("信任的代理: {}", cfg.listen.trusted_proxies.join(", "));
     }
 
+    let users = auth::Users::from_config(&cfg.whitelist);
+    let backends = create_backends(&cfg);
+    if backends.is_empty() && cfg.listen.auth_path.is_empty() {
+        warn!("未配置任何后端，也未开启 auth_request 接口，白名单不会生效");
+    }
     let listcfg = WhiteListServiceConfig {
-        nginx_conf: cfg.whitelist.nginx_conf,
-        nginx_exe: cfg.whitelist.nginx_exe,
-        remote_addr_var: cfg.whitelist.remote_addr_var,
-        result_var: cfg.whitelist.result_var,
         timeout: Duration::from_secs(cfg.whitelist.timeout.into()),
         loop_delay: Duration::from_secs(cfg.whitelist.loop_delay.into()),
         ipv4_prefixlen: cfg.whitelist.ipv4_prefixlen,
         ipv6_prefixlen: cfg.whitelist.ipv6_prefixlen,
         preset: cfg.whitelist.preset,
+        state_file: cfg.whitelist.state_file,
+        owners: users.enabled_names(),
+        sliding: cfg.whitelist.sliding,
     };
     let mut msgsvc: Option<MessageService> = None;
     let mut locsvc: Option<BaiduLocationService> = None;
@@ -64,63 +147,438 @@ async fn main() -> Result<()> {
             referrer: cfg.baidu_location.referrer,
         }))
     }
+    let mut lockout: Option<LockoutService> = None;
+    if cfg.lockout.max_failures != 0 || cfg.lockout.prefix_max_failures != 0 {
+        lockout = Some(LockoutService::new(LockoutServiceConfig {
+            max_failures: cfg.lockout.max_failures,
+            prefix_max_failures: cfg.lockout.prefix_max_failures,
+            ipv4_prefixlen: cfg.lockout.ipv4_prefixlen,
+            ipv6_prefixlen: cfg.lockout.ipv6_prefixlen,
+            window: Duration::from_secs(cfg.lockout.window.into()),
+            ban_time: Duration::from_secs(cfg.lockout.ban_time.into()),
+            max_ban_time: Duration::from_secs(cfg.lockout.max_ban_time.into()),
+            state_file: cfg.lockout.state_file,
+        }));
+    }
+    let mut ratelimit: Option<RateLimitService> = None;
+    let rl = &cfg.rate_limit;
+    if [rl.get, rl.post, rl.api, rl.global]
+        .iter()
+        .any(|i| i.rate > 0.0)
+    {
+        let limit = |i: config::RateLimitRule| RateLimit {
+            rate: i.rate,
+            burst: if i.burst == 0 {
+                i.rate.ceil() as u32
+            } else {
+                i.burst
+            }
+            .max(1),
+        };
+        ratelimit = Some(RateLimitService::new(RateLimitServiceConfig {
+            get: limit(rl.get),
+            post: limit(rl.post),
+            api: limit(rl.api),
+            global: limit(rl.global),
+        }));
+    }
+    // systemd socket 激活时，按地址匹配传入的 socket，没有匹配的才自己监听
+    let mut inherited = systemd::listen_fds();
+    let mut listeners = vec![];
+    for url in cfg.listen.urls {
+        let url = url.into_config();
+        let tls = match (url.cert.is_empty(), url.key.is_empty()) {
+            (true, true) if url.client_ca.is_empty() => None,
+            (false, false) => Some(Arc::new(
+                tls::TlsAcceptor::new(&url.cert, &url.key, &url.client_ca)
+                    .map_err(|err| format!("加载证书 {} 失败: {}", url.cert, err))?,
+            )),
+            _ => return Err(format!("监听地址 {} 需要同时配置 cert 和 key", url.url).into()),
+        };
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let incoming = if let Some(path) = url.url.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let listener = match inherited.take_unix(path.as_ref()) {
+                    Some(l) => {
+                        l.set_nonblocking(true)?;
+                        tokio::net::UnixListener::from_std(l)?
+                    }
+                    None => unixsocket::bind(path.as_ref(), &url.mode, &url.owner)
+                        .map_err(|err| format!("监听 {} 失败: {}", url.url, err))?,
+                };
+                Incoming::Unix(listener)
+            }
+            #[cfg(not(unix))]
+            return Err(format!("当前系统不支持 unix socket: {}", path).into());
+        } else {
+            let addr: SocketAddr = url
+                .url
+                .parse()
+                .map_err(|err| format!("监听地址 {} 无效: {}", url.url, err))?;
+            let listener = match inherited.take_tcp(addr) {
+                Some(l) => {
+                    l.set_nonblocking(true)?;
+                    tokio::net::TcpListener::from_std(l)?
+                }
+                None => tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|err| format!("监听 {} 失败: {}", url.url, err))?,
+            };
+            Incoming::Tcp(listener)
+        };
+        info!("监听 {}://{}", scheme, url.url);
+        listeners.push(Listener {
+            incoming,
+            tls,
+            client_cert_get: url.client_cert_get,
+            source: url.source,
+        });
+    }
+    if !inherited.is_empty() {
+        warn!(
+            "systemd 传入的 {} 个 socket 没有对应的监听地址，已忽略",
+            inherited.len()
+        );
+    }
+    let timeout = listcfg.timeout;
     let ctx = Arc::new(MyAppData {
-        service: Mutex::new(WhiteListService::new(listcfg, msgsvc, locsvc)),
-        token: cfg.whitelist.token,
-        allow_proxy: cfg.listen.allow_proxy,
+        service: Mutex::new(WhiteListService::new(listcfg, backends, msgsvc, locsvc)),
+        users: RwLock::new(users),
+        lockout,
+        ratelimit,
+        trusted_proxies,
+        timeout,
         path: cfg.listen.path,
+        auth_path: cfg.listen.auth_path,
+        api_path: cfg.listen.api_path.trim_end_matches('/').to_string(),
+        tls: listeners.iter().filter_map(|i| i.tls.clone()).collect(),
     });
 
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(ctx.clone()));
+
     let mut tasks = vec![];
-    for addr in cfg.listen.urls {
+    for listener in listeners {
         let ctx = ctx.clone();
         let srv = async move {
-            tokio::spawn(listen_http(ctx, addr.parse().unwrap()))
+            tokio::spawn(listen_http(ctx, listener))
                 .await
                 .unwrap()
                 .unwrap();
         };
         tasks.push(srv.boxed());
     }
-    future::join_all(tasks).await;
+    tokio::select! {
+        _ = future::join_all(tasks) => {}
+        _ = shutdown_signal() => {
+            info!("收到退出信号，保存状态后退出");
+            let stopped = ctx.service.lock().unwrap().stop();
+            stopped.await;
+        }
+    }
 
     Ok(())
 }
 
-async fn listen_http(ctx: Arc<MyAppData>, addr: SocketAddr) -> Result<()> {
-    hyper::Server::bind(&addr)
-        .http1_only(true)
-        .serve(hyper::service::make_service_fn(
-            |socket: &hyper::server::conn::AddrStream| {
-                let remote_addr = socket.remote_addr();
-                let ctx = ctx.clone();
-                async move {
-                    Ok::<_, Error>(hyper::service::service_fn(move |req| {
-                        process_http(ctx.clone(), req, remote_addr).map_err(|err| {
-                            error!("{}", err);
-                            err
-                        })
-                    }))
+/// 等待 SIGTERM（systemctl stop/restart）或 Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
                 }
-            },
-        ))
-        .await?;
-    Ok(())
+                return;
+            }
+            Err(err) => error!("监听 SIGTERM 失败: {}", err),
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        error!("监听 Ctrl-C 失败: {}", err);
+        future::pending::<()>().await;
+    }
+}
+
+/// 收到 SIGHUP 时重新加载用户列表和证书，被删除或禁用的用户的记录会立即删除
+#[cfg(unix)]
+async fn reload_on_sighup(ctx: Arc<MyAppData>) {
+    let mut sig = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            error!("监听 SIGHUP 失败: {}", err);
+            return;
+        }
+    };
+    while sig.recv().await.is_some() {
+        info!("收到 SIGHUP，重新加载用户列表和证书");
+        for tls in &ctx.tls {
+            if let Err(err) = tls.reload() {
+                error!("重新加载证书失败，继续使用原来的证书: {}", err);
+            }
+        }
+        let cfg = match config::load_config() {
+            Ok(cfg) => cfg,
+            Err(err) => {
+                error!("重新加载配置失败: {}", err);
+                continue;
+            }
+        };
+        let users = auth::Users::from_config(&cfg.whitelist);
+        ctx.service
+            .lock()
+            .unwrap()
+            .retain_owners(users.enabled_names());
+        ctx.users.write().unwrap().reload(users);
+    }
+}
+
+fn create_backends(cfg: &config::Config) -> Vec<Box<dyn Backend>> {
+    let mut backends: Vec<Box<dyn Backend>> = vec![];
+    if !cfg.whitelist.nginx_conf.is_empty() {
+        backends.push(Box::new(NginxBackend::new(NginxBackendConfig {
+            conf: cfg.whitelist.nginx_conf.clone(),
+            exe: cfg.whitelist.nginx_exe.clone(),
+            remote_addr_var: cfg.whitelist.remote_addr_var.clone(),
+            result_var: cfg.whitelist.result_var.clone(),
+        })));
+    }
+    for b in &cfg.backends {
+        let backend: Box<dyn Backend> = match b {
+            config::BackendConfig::Nginx(c) => Box::new(NginxBackend::new(NginxBackendConfig {
+                conf: c.conf.clone(),
+                exe: c.exe.clone(),
+                remote_addr_var: c.remote_addr_var.clone(),
+                result_var: c.result_var.clone(),
+            })),
+            config::BackendConfig::Nftables(c) => {
+                Box::new(NftablesBackend::new(NftablesBackendConfig {
+                    exe: c.exe.clone(),
+                    family: c.family.clone(),
+                    table: c.table.clone(),
+                    set4: c.set4.clone(),
+                    set6: c.set6.clone(),
+                }))
+            }
+            config::BackendConfig::Haproxy(c) => {
+                Box::new(HaproxyBackend::new(HaproxyBackendConfig {
+                    acl_file: c.acl_file.clone(),
+                    socket: c.socket.clone(),
+                }))
+            }
+            config::BackendConfig::Caddy(c) => Box::new(CaddyBackend::new(CaddyBackendConfig {
+                admin: c.admin.clone(),
+                path: c.path.clone(),
+            })),
+            config::BackendConfig::Traefik(c) => {
+                Box::new(TraefikBackend::new(TraefikBackendConfig {
+                    path: c.path.clone(),
+                    middleware: c.middleware.clone(),
+                }))
+            }
+            config::BackendConfig::Apache(c) => Box::new(ApacheBackend::new(ApacheBackendConfig {
+                conf: c.conf.clone(),
+                exe: c.exe.clone(),
+                cwd: c.cwd.clone(),
+                test_args: c.test_args.clone(),
+                reload_args: c.reload_args.clone(),
+            })),
+            config::BackendConfig::Template(c) => {
+                Box::new(TemplateBackend::new(TemplateBackendConfig {
+                    name: c.name.clone(),
+                    template: c.template.clone(),
+                    output: c.output.clone(),
+                    validate: c.validate.clone(),
+                    reload: c.reload.clone(),
+                }))
+            }
+        };
+        backends.push(backend);
+    }
+    backends
+}
+
+async fn listen_http(ctx: Arc<MyAppData>, listener: Listener) -> Result<()> {
+    let mut http = hyper::server::conn::Http::new();
+    http.http1_only(true);
+    match &listener.incoming {
+        Incoming::Tcp(incoming) => loop {
+            match incoming.accept().await {
+                Ok((stream, remote_addr)) => {
+                    let conn = listener.conn_info(remote_addr, false);
+                    let tls = listener.tls.as_ref().map(|i| i.acceptor());
+                    tokio::spawn(accept_connection(
+                        ctx.clone(),
+                        http.clone(),
+                        tls,
+                        stream,
+                        conn,
+                    ));
+                }
+                Err(err) => accept_failed(err).await,
+            }
+        },
+        #[cfg(unix)]
+        Incoming::Unix(incoming) => loop {
+            match incoming.accept().await {
+                Ok((stream, _)) => {
+                    let remote_addr = SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+                    let conn = listener.conn_info(remote_addr, true);
+                    let tls = listener.tls.as_ref().map(|i| i.acceptor());
+                    tokio::spawn(accept_connection(
+                        ctx.clone(),
+                        http.clone(),
+                        tls,
+                        stream,
+                        conn,
+                    ));
+                }
+                Err(err) => accept_failed(err).await,
+            }
+        },
+    }
+}
+
+async fn accept_failed(err: std::io::Error) {
+    // 多半是文件句柄耗尽，稍等再继续
+    error!("接受连接失败: {}", err);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+}
+
+async fn accept_connection<S>(
+    ctx: Arc<MyAppData>,
+    http: hyper::server::conn::Http,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    stream: S,
+    mut conn: ConnInfo,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    if conn.source != AddressSource::ProxyProtocol {
+        return accept_tls(ctx, http, tls, stream, conn).await;
+    }
+    let peer = conn.remote_addr;
+    if !conn.proxied && !is_trusted_proxy(&ctx.trusted_proxies, &peer.ip()) {
+        warn!("{} 不是受信任的代理，拒绝 PROXY 协议连接", peer);
+        return;
+    }
+    let mut stream = tokio::io::BufReader::new(stream);
+    let header = tokio::time::timeout(
+        Duration::from_secs(10),
+        proxyprotocol::read_header(&mut stream),
+    )
+    .await;
+    match header {
+        Ok(Ok(Some(addr))) => conn.remote_addr = addr,
+        Ok(Ok(None)) => {}
+        Ok(Err(err)) => {
+            debug!("{} 读取 PROXY 协议头部失败: {}", peer, err);
+            return;
+        }
+        Err(_) => {
+            debug!("{} 读取 PROXY 协议头部超时", peer);
+            return;
+        }
+    }
+    accept_tls(ctx, http, tls, stream, conn).await
+}
+
+async fn accept_tls<S>(
+    ctx: Arc<MyAppData>,
+    http: hyper::server::conn::Http,
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    stream: S,
+    mut conn: ConnInfo,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let remote_addr = conn.remote_addr;
+    let result = match tls {
+        Some(tls) => match tls.accept(stream).await {
+            Ok(stream) => {
+                conn.cert_names = tls::peer_names(stream.get_ref().1);
+                serve_connection(&http, stream, ctx, conn).await
+            }
+            Err(err) => {
+                debug!("{} TLS 握手失败: {}", remote_addr, err);
+                return;
+            }
+        },
+        None => serve_connection(&http, stream, ctx, conn).await,
+    };
+    if let Err(err) = result {
+        debug!("{} 连接出错: {}", remote_addr, err);
+    }
+}
+
+async fn serve_connection<S>(
+    http: &hyper::server::conn::Http,
+    stream: S,
+    ctx: Arc<MyAppData>,
+    conn: ConnInfo,
+) -> hyper::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let conn = Arc::new(conn);
+    let service =
+        hyper::service::service_fn(move |req| handle_http(ctx.clone(), req, conn.clone()));
+    http.serve_connection(stream, service).await
+}
+
+async fn handle_http(
+    ctx: Arc<MyAppData>,
+    req: Request<Body>,
+    conn: Arc<ConnInfo>,
+) -> Result<Response<Body>> {
+    let result = process_http(ctx, req, conn).await;
+    if let Err(err) = &result {
+        error!("{}", err);
+    }
+    result
 }
 
 async fn process_http(
     ctx: Arc<MyAppData>,
     req: Request<Body>,
-    remote_addr: SocketAddr,
+    conn: Arc<ConnInfo>,
 ) -> Result<Response<Body>> {
     let ua = req
         .headers()
         .get(hyper::header::USER_AGENT)
         .map(|ua| ua.as_bytes())
-        .or(Some(&[]))
-        .unwrap();
+        .unwrap_or(&[]);
     let ua = String::from_utf8_lossy(ua);
-    let ip = get_remote_ip(&req, &remote_addr, ctx.allow_proxy);
+    let ip = get_remote_ip(&req, &conn, &ctx.trusted_proxies);
+    let is_auth = !ctx.auth_path.is_empty() && req.uri().path() == ctx.auth_path;
+    let is_api = !ctx.api_path.is_empty()
+        && req
+            .uri()
+            .path()
+            .strip_prefix(&ctx.api_path)
+            .is_some_and(|i| i.is_empty() || i.starts_with('/'));
+    if let Some(ratelimit) = &ctx.ratelimit {
+        let class = if is_auth || is_api {
+            RateClass::Api
+        } else if req.method() == Method::POST {
+            RateClass::Post
+        } else {
+            RateClass::Get
+        };
+        if let Err(wait) = ratelimit.check(&ip, class) {
+            debug!("{} 请求过于频繁", ip);
+            return Ok(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(hyper::header::RETRY_AFTER, wait.as_secs() + 1)
+                .body(Body::empty())?);
+        }
+    }
+    if is_auth {
+        return auth(ctx, ip).await;
+    }
     info!(
         "{} \"{} {} {:?}\" \"{}\"",
         ip,
@@ -129,12 +587,32 @@ async fn process_http(
         req.version(),
         ua
     );
-    if req.uri().path() != ctx.path {
+    if req.uri().path() != ctx.path && !is_api {
         return Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::empty())?);
     }
+    if let Some(remaining) = ctx.lockout.as_ref().and_then(|l| l.check(&ip)) {
+        debug!("{} 已被封禁，剩余 {} 秒", ip, remaining.as_secs());
+        return Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(hyper::header::RETRY_AFTER, remaining.as_secs() + 1)
+            .body(Body::empty())?);
+    }
+    if is_api {
+        return api::handle(ctx, req, ip).await;
+    }
+    if *req.method() == Method::GET && req.uri().query() == Some("status") {
+        return status::handle(&ctx, &req, ip).await;
+    }
+    let cert_allowed =
+        *req.method() == Method::POST || (*req.method() == Method::GET && conn.client_cert_get);
     let (status, body) = match *req.method() {
+        Method::POST if req.uri().query() == Some("logout") => logout(ctx, req, ip).await?,
+        Method::POST if req.uri().query() == Some("renew") => renew(ctx, ip).await,
+        _ if cert_allowed && cert_login(&ctx, &conn, ip) => {
+            (StatusCode::OK, status::page(&ctx, ip).await.into())
+        }
         Method::GET => (StatusCode::OK, Body::from(get())),
         Method::POST => post(ctx, req, ip).await?,
         _ => (StatusCode::METHOD_NOT_ALLOWED, Body::empty()),
@@ -145,6 +623,38 @@ async fn process_http(
         .body(body)?)
 }
 
+/// 供 nginx 的 auth_request 调用，在白名单中返回 204，否则返回 403
+async fn auth(ctx: Arc<MyAppData>, ip: IpAddr) -> Result<Response<Body>> {
+    let allowed = ctx.service.lock().unwrap().check(ip);
+    let status = if allowed.await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::FORBIDDEN
+    };
+    debug!("auth_request: {} {}", ip, status.as_u16());
+    Ok(Response::builder().status(status).body(Body::empty())?)
+}
+
+/// 客户端证书对应某个用户时，不需要 token 直接加入白名单
+fn cert_login(ctx: &MyAppData, conn: &ConnInfo, ip: IpAddr) -> bool {
+    if conn.cert_names.is_empty() {
+        return false;
+    }
+    let user = ctx
+        .users
+        .read()
+        .unwrap()
+        .find_by_cert(&conn.cert_names)
+        .map(|u| (u.name.clone(), u.clamp_ttl(ctx.timeout)));
+    let Some((user, ttl)) = user else {
+        debug!("客户端证书 {:?} 没有对应的用户", conn.cert_names);
+        return false;
+    };
+    info!("用户 {} 使用客户端证书提交了 {}", user, ip);
+    ctx.service.lock().unwrap().push(ip, &user, ttl);
+    true
+}
+
 fn get() -> &'static str {
     r#"<!DOCTYPE html>
 <html lang="zh">
@@ -158,7 +668,12 @@ fn get() -> &'static str {
     <form method="POST">
         <label for="token">token: </label>
         <input name="token" id="token"/>
+        <label for="code">验证码: </label>
+        <input name="code" id="code" inputmode="numeric" autocomplete="one-time-code"/>
+        <label for="ttl">时长（秒）: </label>
+        <input name="ttl" id="ttl" inputmode="numeric" placeholder="默认"/>
         <button type="submit">提交</button>
+        <button type="submit" formaction="?logout">注销</button>
     </form>
 </body>
 </html>
@@ -176,36 +691,273 @@ async fn post(
     }
     let body = hyper::body::aggregate(req.body_mut()).await?;
     let form: HashMap<String, String> = serde_urlencoded::from_reader(body.reader())?;
-    let token = form.get("token");
-    if token == Some(&ctx.token) {
-        ctx.service.lock().unwrap().push(ip);
-        Ok((StatusCode::OK, "hello".into()))
+    let token = form.get("token").map(|t| t.as_str()).unwrap_or_default();
+    let code = form.get("code").map(|t| t.as_str()).unwrap_or_default();
+    // 留空使用 timeout，超过用户的 max_ttl 时按 max_ttl 处理
+    let ttl = match form.get("ttl").map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        None => ctx.timeout,
+        Some(ttl) => match ttl.parse::<u64>() {
+            Ok(ttl) if ttl > 0 => Duration::from_secs(ttl),
+            _ => return Ok((StatusCode::BAD_REQUEST, Body::empty())),
+        },
+    };
+    if let Some(user) = authenticate(&ctx, ip, token, code) {
+        let ttl = user.clamp_ttl(ttl);
+        info!(
+            "用户 {} 提交了 {}，有效期 {} 秒",
+            user.name,
+            ip,
+            ttl.as_secs()
+        );
+        ctx.service.lock().unwrap().push(ip, &user.name, ttl);
+        Ok((StatusCode::OK, status::page(&ctx, ip).await.into()))
     } else {
-        warn!("未授权访问：{}", ip);
         Ok((StatusCode::FORBIDDEN, Body::empty()))
     }
 }
 
-fn get_remote_ip(req: &Request<Body>, remote_addr: &SocketAddr, allow_proxy: bool) -> IpAddr {
-    let mut ip: Option<IpAddr> = None;
-    if allow_proxy {
-        if let Some(f) = req.headers().get("X-Forwarded-For") {
-            if let Ok(f) = f.to_str() {
-                let addr = if let Some((f, _)) = f.split_once(",") {
-                    f.trim()
-                } else {
-                    f.trim()
-                };
-                if let Ok(addr) = addr.parse::<IpAddr>() {
-                    ip = Some(addr);
-                } else if let Ok(addr) = addr.parse::<SocketAddr>() {
-                    ip = Some(addr.ip());
-                }
-            }
+/// 校验 token 和验证码，并记录到防爆破
+fn authenticate(ctx: &MyAppData, ip: IpAddr, token: &str, code: &str) -> Option<auth::User> {
+    let user = ctx.find_user(|users| users.authenticate(token, code));
+    if let Some(lockout) = &ctx.lockout {
+        match user {
+            Some(_) => lockout.success(&ip),
+            None => lockout.failure(&ip),
         }
     }
-    if ip.is_none() {
-        ip = Some(remote_addr.ip());
+    if user.is_none() {
+        warn!("未授权访问：{}", ip);
+    }
+    user
+}
+
+/// 注销：立即删除包含当前 IP 的项，只能删除自己所在的地址
+///
+/// 不带 token 时只删除提交 token 或客户端证书添加的项，带上有效的 token 时也删除通过接口添加的网段。
+async fn logout(
+    ctx: Arc<MyAppData>,
+    mut req: Request<Body>,
+    ip: IpAddr,
+) -> Result<(StatusCode, Body)> {
+    let form: HashMap<String, String> = if matches!(req.headers().get(hyper::header::CONTENT_TYPE), Some(ct) if ct == "application/x-www-form-urlencoded")
+    {
+        let body = hyper::body::aggregate(req.body_mut()).await?;
+        serde_urlencoded::from_reader(body.reader())?
+    } else {
+        HashMap::new()
+    };
+    let token = form.get("token").map(|t| t.as_str()).unwrap_or_default();
+    let code = form.get("code").map(|t| t.as_str()).unwrap_or_default();
+    let user = if token.is_empty() {
+        None
+    } else {
+        let Some(user) = authenticate(&ctx, ip, token, code) else {
+            return Ok((StatusCode::FORBIDDEN, Body::empty()));
+        };
+        Some(user.name)
+    };
+    let removed = ctx.service.lock().unwrap().logout(ip, user.is_some());
+    if removed.await.is_empty() {
+        debug!("{} 不在白名单中，无需注销", ip);
+        return Ok((StatusCode::NOT_FOUND, Body::empty()));
+    }
+    if let Some(user) = user {
+        info!("用户 {} 注销了 {}", user, ip);
+    }
+    Ok((StatusCode::OK, "bye".into()))
+}
+
+/// 主动续期，同注销一样只能续期自己所在地址的项，需要开启滑动续期
+async fn renew(ctx: Arc<MyAppData>, ip: IpAddr) -> (StatusCode, Body) {
+    let renewed = ctx.service.lock().unwrap().renew(ip);
+    if renewed.await.is_empty() {
+        debug!("{} 不在白名单中或没有开启滑动续期", ip);
+        (StatusCode::NOT_FOUND, Body::empty())
+    } else {
+        info!("{} 续期了白名单", ip);
+        (StatusCode::OK, status::page(&ctx, ip).await.into())
+    }
+}
+
+/// 获取客户端地址
+///
+/// 只有直接连接的对端是受信任的代理时才读取监听地址配置的 http 头，从右向左跳过受信任的代理，
+/// 第一个不受信任的地址就是客户端，全部受信任时取最左边的地址。
+/// 使用 PROXY 协议时，连接建立时已经得到了客户端地址。
+fn get_remote_ip(req: &Request<Body>, conn: &ConnInfo, trusted_proxies: &[IpNet]) -> IpAddr {
+    let mut ip = conn.remote_addr.ip().to_canonical();
+    if conn.source == AddressSource::ProxyProtocol
+        || (!conn.proxied && !is_trusted_proxy(trusted_proxies, &ip))
+    {
+        return ip;
+    }
+    let headers = req.headers();
+    let hops: Vec<&str> = match conn.source {
+        AddressSource::XForwardedFor => headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .collect(),
+        AddressSource::Forwarded => headers
+            .get_all(hyper::header::FORWARDED)
+            .iter()
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .map(forwarded_for)
+            .collect(),
+        AddressSource::XRealIp => headers
+            .get_all("X-Real-IP")
+            .iter()
+            .next_back()
+            .map(|v| v.to_str().unwrap_or_default())
+            .into_iter()
+            .collect(),
+        AddressSource::ProxyProtocol => vec![],
+    };
+    for hop in hops.into_iter().rev() {
+        let Some(hop) = parse_forwarded_ip(hop.trim()) else {
+            return ip;
+        };
+        ip = hop;
+        if !is_trusted_proxy(trusted_proxies, &ip) {
+            return ip;
+        }
+    }
+    ip
+}
+
+fn is_trusted_proxy(trusted_proxies: &[IpNet], ip: &IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// 取出 `Forwarded` 头中一个元素的 `for=` 参数，如 `for="[2001:db8::1]:4711";proto=https`
+fn forwarded_for(element: &str) -> &str {
+    element
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("for"))
+        .map(|(_, v)| v.trim().trim_matches('"'))
+        .unwrap_or_default()
+}
+
+/// 解析 `1.2.3.4`、`1.2.3.4:5678`、`[::1]`、`[::1]:5678` 这样的地址
+fn parse_forwarded_ip(addr: &str) -> Option<IpAddr> {
+    let unbracketed = addr
+        .strip_prefix('[')
+        .and_then(|i| i.strip_suffix(']'))
+        .unwrap_or(addr);
+    let ip = unbracketed
+        .parse::<IpAddr>()
+        .or_else(|_| addr.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()?;
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(remote: &str, source: AddressSource) -> ConnInfo {
+        ConnInfo {
+            remote_addr: remote.parse().unwrap(),
+            proxied: false,
+            cert_names: vec![],
+            client_cert_get: false,
+            source,
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder();
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn nets(v: &[&str]) -> Vec<IpNet> {
+        v.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_values() {
+        assert_eq!(forwarded_for("for=192.0.2.60;proto=http"), "192.0.2.60");
+        assert_eq!(
+            forwarded_for(r#" proto=https; For="[2001:db8::1]:4711""#),
+            "[2001:db8::1]:4711"
+        );
+        assert_eq!(forwarded_for("by=203.0.113.43"), "");
+        assert_eq!(parse_forwarded_ip("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(
+            parse_forwarded_ip("192.0.2.60:8080"),
+            Some(ip("192.0.2.60"))
+        );
+        assert_eq!(parse_forwarded_ip("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(
+            parse_forwarded_ip("[2001:db8::1]:4711"),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(
+            parse_forwarded_ip("::ffff:192.0.2.60"),
+            Some(ip("192.0.2.60"))
+        );
+        assert_eq!(parse_forwarded_ip("unknown"), None);
+        assert_eq!(parse_forwarded_ip("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let req = request(&[(
+            "Forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for="10.0.0.2:1234""#,
+        )]);
+        let c = conn("10.0.0.1:80", AddressSource::Forwarded);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn skip_trusted_hops_from_right() {
+        let trusted = nets(&["10.0.0.0/8", "fd00::/8"]);
+        let c = conn("10.0.0.1:80", AddressSource::XForwardedFor);
+        // 最左边的地址可以由客户端伪造，取最右边不受信任的地址
+        let req = request(&[
+            ("X-Forwarded-For", "1.1.1.1, 2.2.2.2"),
+            ("X-Forwarded-For", "10.0.0.3, fd00::1"),
+        ]);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("2.2.2.2"));
+        // 全部受信任时取最左边的地址
+        let req = request(&[("X-Forwarded-For", "10.0.0.4, 10.0.0.3")]);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("10.0.0.4"));
+        // 无法解析的地址之后的内容都不可信
+        let req = request(&[("X-Forwarded-For", "1.1.1.1, unknown, 10.0.0.3")]);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("10.0.0.3"));
+        // 没有 http 头时使用对端地址
+        assert_eq!(get_remote_ip(&request(&[]), &c, &trusted), ip("10.0.0.1"));
+
+        let c = conn("10.0.0.1:80", AddressSource::XRealIp);
+        let req = request(&[("X-Real-IP", "1.1.1.1"), ("X-Real-IP", "3.3.3.3")]);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("3.3.3.3"));
+    }
+
+    #[test]
+    fn ignore_headers_from_untrusted_peer() {
+        let trusted = nets(&["10.0.0.0/8"]);
+        let req = request(&[("X-Forwarded-For", "1.1.1.1"), ("Forwarded", "for=1.1.1.1")]);
+        let c = conn("[::ffff:2.2.2.2]:80", AddressSource::XForwardedFor);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("2.2.2.2"));
+        let c = conn("2.2.2.2:80", AddressSource::Forwarded);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("2.2.2.2"));
+        // 本机反向代理的连接总是读取 http 头
+        let mut c = conn("127.0.0.1:0", AddressSource::XForwardedFor);
+        c.proxied = true;
+        assert_eq!(get_remote_ip(&req, &c, &[]), ip("1.1.1.1"));
+        // PROXY 协议的地址已经是客户端地址
+        let c = conn("10.0.0.1:80", AddressSource::ProxyProtocol);
+        assert_eq!(get_remote_ip(&req, &c, &trusted), ip("10.0.0.1"));
     }
-    ip.unwrap()
 }
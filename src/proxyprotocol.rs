@@ -0,0 +1,194 @@
+//! HAProxy PROXY 协议，支持 v1（文本）和 v2（二进制），只解析源地址
+
+use crate::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// v1 头部最长 107 字节
+const V1_MAX_LEN: u64 = 107;
+
+/// 读取 PROXY 协议头部，返回客户端地址，`LOCAL` 或 `UNKNOWN` 连接返回 `None`
+///
+/// 使用带缓冲的读取，头部之后的数据留在缓冲区中，不会丢失。
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncBufRead + AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY" {
+        let mut line = vec![];
+        stream
+            .take(V1_MAX_LEN - prefix.len() as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        let line = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|i| i.strip_suffix("\r\n"))
+            .ok_or("PROXY v1 头部格式错误")?;
+        parse_v1(line)
+    } else if prefix == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 11];
+        stream.read_exact(&mut header).await?;
+        if header[..7] != V2_SIGNATURE[5..] {
+            return Err("PROXY v2 签名错误".into());
+        }
+        let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(header[7], header[8], &body)
+    } else {
+        Err("不是 PROXY 协议".into())
+    }
+}
+
+/// 解析 ` TCP4 192.0.2.1 198.51.100.1 56324 443` 这样的内容
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').skip(1).collect();
+    match parts.first() {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 5 => {
+            let ip: IpAddr = parts[1].parse()?;
+            let port: u16 = parts[3].parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("PROXY v1 头部格式错误: {}", line).into()),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(format!("不支持的 PROXY 协议版本: {}", ver_cmd >> 4).into());
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL，负载均衡器自己的连接，比如健康检查
+        0 => return Ok(None),
+        1 => {}
+        cmd => return Err(format!("不支持的 PROXY v2 命令: {}", cmd).into()),
+    }
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_UNSPEC 或 AF_UNIX，没有可用的 IP
+        0 | 3 => Ok(None),
+        _ => Err("PROXY v2 地址格式错误".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    /// 解析头部，并返回头部之后剩余的数据
+    async fn read(data: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = BufReader::new(data);
+        let addr = read_header(&mut stream).await?;
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await?;
+        Ok((addr, rest))
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([ver_cmd, family]);
+        data.extend((body.len() as u16).to_be_bytes());
+        data.extend(body);
+        data.extend(b"GET /");
+        data
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (addr, rest) =
+            read(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\nGET /")
+                .await
+                .unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        // 缺少 \r\n
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443")
+            .await
+            .is_err());
+        // 缺少端口
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.x 198.51.100.1 56324 443\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+            .await
+            .is_err());
+        // 超过最大长度
+        let mut long = b"PROXY TCP6 ".to_vec();
+        long.extend([b'1'; 120]);
+        long.extend(b"\r\n");
+        assert!(read(&long).await.is_err());
+        assert!(read(b"PROX").await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_inet() {
+        let (addr, rest) = read(&v2(0x20, 0x00, &[])).await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET /");
+
+        let body = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let (addr, rest) = read(&v2(0x21, 0x11, &body)).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut body = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        body.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend([0xdc, 0x04, 0x01, 0xbb]);
+        // 地址之后的 TLV 一起跳过
+        body.extend([0x04, 0x00, 0x01, 0x00]);
+        let (addr, rest) = read(&v2(0x21, 0x21, &body)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        // 地址长度不足
+        let body = [192, 0, 2, 1, 198, 51, 100, 1];
+        assert!(read(&v2(0x21, 0x11, &body)).await.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 20])).await.is_err());
+        // 版本、命令错误
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x22, 0x11, &[0; 12])).await.is_err());
+        // 数据被截断
+        let data = v2(0x21, 0x11, &[0; 12]);
+        assert!(read(&data[..20]).await.is_err());
+        assert!(read(&data[..14]).await.is_err());
+        let mut data = data;
+        data[6] = b'x';
+        assert!(read(&data).await.is_err());
+    }
+}
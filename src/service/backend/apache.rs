@@ -0,0 +1,86 @@
+use super::{exe_dir, run_command, Backend, ConfigFile, Snapshot};
+use crate::Result;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct ApacheBackendConfig {
+    pub conf: String,
+    pub exe: String,
+    pub cwd: String,
+    /// 测试配置的参数，默认为 `configtest`
+    pub test_args: Vec<String>,
+    /// 平滑重载的参数，默认为 `graceful`
+    pub reload_args: Vec<String>,
+}
+
+/// 生成 Apache httpd 的 `Require ip` 配置片段，通过 `apachectl configtest` 测试，`apachectl graceful` 刷新
+///
+/// 测试和刷新的参数可以配置，直接使用 `httpd` 时为 `-t` 和 `-k graceful`。
+pub struct ApacheBackend {
+    config: ApacheBackendConfig,
+    file: ConfigFile,
+}
+
+impl ApacheBackend {
+    pub fn new(config: ApacheBackendConfig) -> Self {
+        ApacheBackend {
+            file: ConfigFile::new(&config.conf),
+            config,
+        }
+    }
+
+    fn cwd(&self) -> Option<&Path> {
+        if self.config.cwd.is_empty() {
+            exe_dir(&self.config.exe)
+        } else {
+            Some(Path::new(&self.config.cwd))
+        }
+    }
+}
+
+impl Backend for ApacheBackend {
+    fn name(&self) -> &str {
+        "apache"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        let mut s = String::new();
+        s.push_str("<RequireAny>\n");
+        for i in snapshot.preset {
+            s.push_str(&format!("    Require ip {}\n", i));
+        }
+        for i in snapshot.entries {
+            s.push_str(&format!("    Require ip {}\n", i.cidr));
+        }
+        // RequireAny 中至少要有一条指令，否则配置测试无法通过
+        if snapshot.preset.is_empty() && snapshot.entries.is_empty() {
+            s.push_str("    Require all denied\n");
+        }
+        s.push_str("</RequireAny>\n");
+        Ok(s)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.file.write(content)
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        run_command(&self.config.exe, &args(&self.config.test_args), self.cwd())
+    }
+
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        run_command(
+            &self.config.exe,
+            &args(&self.config.reload_args),
+            self.cwd(),
+        )
+    }
+
+    fn rollback(&mut self) -> Result<bool> {
+        self.file.restore()
+    }
+}
+
+fn args(args: &[String]) -> Vec<&str> {
+    args.iter().map(|i| i.as_str()).collect()
+}
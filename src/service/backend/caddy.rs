@@ -0,0 +1,63 @@
+use super::{Backend, Snapshot};
+use crate::Result;
+
+#[derive(Clone)]
+pub struct CaddyBackendConfig {
+    pub admin: String,
+    pub path: String,
+}
+
+/// 通过 Caddy 的管理 API 更新 `remote_ip` 匹配器的 `ranges`，不写文件也不创建进程
+///
+/// `path` 需指向配置中已存在的 `ranges` 数组，例如
+/// `/config/apps/http/servers/srv0/routes/0/match/0/remote_ip/ranges`。
+pub struct CaddyBackend {
+    config: CaddyBackendConfig,
+    body: String,
+}
+
+impl CaddyBackend {
+    pub fn new(config: CaddyBackendConfig) -> Self {
+        CaddyBackend {
+            config,
+            body: String::new(),
+        }
+    }
+}
+
+impl Backend for CaddyBackend {
+    fn name(&self) -> &str {
+        "caddy"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        let mut ranges: Vec<&str> = snapshot.preset.iter().map(|i| i.as_str()).collect();
+        ranges.extend(snapshot.entries.iter().map(|i| i.cidr.as_str()));
+        Ok(serde_json::to_string(&ranges)?)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.body = content.to_string();
+        Ok(())
+    }
+
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        let url = format!(
+            "{}{}",
+            self.config.admin.trim_end_matches('/'),
+            self.config.path
+        );
+        let resp = ureq::request("PATCH", &url)
+            .set("Content-Type", "application/json")
+            .timeout(std::time::Duration::from_secs(15))
+            .send_string(&self.body);
+        match resp {
+            Ok(_) => Ok(()),
+            // Caddy 会在响应中给出具体的错误原因
+            Err(ureq::Error::Status(code, resp)) => {
+                Err(format!("{} {}", code, resp.into_string().unwrap_or_default().trim()).into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
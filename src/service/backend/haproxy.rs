@@ -0,0 +1,343 @@
+use super::{Backend, ConfigFile, Snapshot};
+use crate::Result;
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+#[derive(Clone)]
+pub struct HaproxyBackendConfig {
+    pub acl_file: String,
+    pub socket: String,
+}
+
+/// 写出 HAProxy 的 ACL 文件，并通过运行时 API 增量更新内存中的 ACL，无需重载 HAProxy
+///
+/// ACL 文件用于 HAProxy 重启后加载，运行时 API 中以文件路径作为 ACL 的标识。
+pub struct HaproxyBackend {
+    config: HaproxyBackendConfig,
+    file: ConfigFile,
+    /// 运行时 API 中的 ACL 是否已与列表同步，未同步时先清空再全量添加
+    synced: bool,
+}
+
+impl HaproxyBackend {
+    pub fn new(config: HaproxyBackendConfig) -> Self {
+        HaproxyBackend {
+            file: ConfigFile::new(&config.acl_file),
+            config,
+            synced: false,
+        }
+    }
+
+    fn command(&self, cmd: &str) -> Result<()> {
+        debug!("[haproxy] {}", cmd);
+        let resp = send_command(&self.config.socket, cmd)?;
+        let resp = resp.trim();
+        // 执行成功时没有输出
+        if !resp.is_empty() {
+            return Err(format!("{}: {}", cmd, resp).into());
+        }
+        Ok(())
+    }
+
+    fn sync(&self, snapshot: &Snapshot) -> Result<()> {
+        let acl = &self.config.acl_file;
+        if !self.synced {
+            self.command(&format!("clear acl {}", acl))?;
+            for i in snapshot.preset {
+                self.command(&format!("add acl {} {}", acl, i))?;
+            }
+            for i in snapshot.entries {
+                self.command(&format!("add acl {} {}", acl, i.cidr))?;
+            }
+        } else {
+            for i in snapshot.removed {
+                self.command(&format!("del acl {} {}", acl, i))?;
+            }
+            for i in snapshot.added {
+                self.command(&format!("add acl {} {}", acl, i))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for HaproxyBackend {
+    fn name(&self) -> &str {
+        "haproxy"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        let mut s = String::new();
+        for i in snapshot.preset {
+            s.push_str(&format!("{}\n", i));
+        }
+        for i in snapshot.entries {
+            s.push_str(&format!("{}\n", i.cidr));
+        }
+        Ok(s)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        let result = self.file.write(content);
+        // 失败时不会调用 reload，这次的增量变化没有同步到运行时 API，下次全量同步
+        if result.is_err() {
+            self.synced = false;
+        }
+        result
+    }
+
+    fn reload(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if self.config.socket.is_empty() {
+            return Ok(());
+        }
+        let result = self.sync(snapshot);
+        // 失败后运行时状态不确定，下次全量同步
+        self.synced = result.is_ok();
+        result
+    }
+
+    fn rollback(&mut self) -> Result<bool> {
+        self.file.restore()
+    }
+}
+
+/// 向运行时 API 发送一条命令，返回全部输出
+///
+/// `socket` 可以是 Unix 套接字路径，也可以是 `ip:port` 形式的 TCP 地址。
+fn send_command(socket: &str, cmd: &str) -> Result<String> {
+    let timeout = Some(Duration::from_secs(5));
+    let mut resp = String::new();
+    if let Ok(addr) = socket.parse::<std::net::SocketAddr>() {
+        let mut stream = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+        stream.set_read_timeout(timeout)?;
+        stream.write_all(format!("{}\n", cmd).as_bytes())?;
+        stream.read_to_string(&mut resp)?;
+        return Ok(resp);
+    }
+    #[cfg(unix)]
+    {
+        let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+        stream.set_read_timeout(timeout)?;
+        stream.write_all(format!("{}\n", cmd).as_bytes())?;
+        stream.read_to_string(&mut resp)?;
+        Ok(resp)
+    }
+    #[cfg(not(unix))]
+    {
+        Err("当前平台不支持 Unix 套接字".into())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::service::backend::Entry;
+    use std::{
+        io::BufRead,
+        os::unix::net::UnixListener,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    /// 模拟 HAProxy 运行时 API，每个连接处理一条命令，记录收到的命令，`fail` 中的命令返回错误
+    struct FakeHaproxy {
+        dir: PathBuf,
+        commands: Arc<Mutex<Vec<String>>>,
+        fail: Arc<Mutex<Option<String>>>,
+    }
+
+    impl FakeHaproxy {
+        fn start(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let listener = UnixListener::bind(dir.join("haproxy.sock")).unwrap();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let fail = Arc::new(Mutex::new(None::<String>));
+            let (c, f) = (commands.clone(), fail.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut line = String::new();
+                    std::io::BufReader::new(&stream)
+                        .read_line(&mut line)
+                        .unwrap();
+                    let cmd = line.trim_end().to_string();
+                    let resp = if f.lock().unwrap().as_ref() == Some(&cmd) {
+                        "Unknown ACL\n\n"
+                    } else {
+                        "\n"
+                    };
+                    c.lock().unwrap().push(cmd);
+                    let _ = stream.write_all(resp.as_bytes());
+                }
+            });
+            FakeHaproxy {
+                dir,
+                commands,
+                fail,
+            }
+        }
+
+        fn backend(&self) -> HaproxyBackend {
+            HaproxyBackend::new(HaproxyBackendConfig {
+                acl_file: self.dir.join("wl.acl").to_string_lossy().into_owned(),
+                socket: self.dir.join("haproxy.sock").to_string_lossy().into_owned(),
+            })
+        }
+
+        /// 取出并清空已收到的命令，把 ACL 文件路径替换为 `F`
+        fn take(&self) -> Vec<String> {
+            let acl = self.dir.join("wl.acl").to_string_lossy().into_owned();
+            std::mem::take(&mut *self.commands.lock().unwrap())
+                .into_iter()
+                .map(|c| c.replace(&acl, "F"))
+                .collect()
+        }
+    }
+
+    impl Drop for FakeHaproxy {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn entries(cidrs: &[&str]) -> Vec<Entry> {
+        cidrs
+            .iter()
+            .map(|c| Entry {
+                addr: c.split('/').next().unwrap().parse().unwrap(),
+                cidr: c.to_string(),
+                expire: SystemTime::now(),
+                owner: String::new(),
+            })
+            .collect()
+    }
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// 依次调用 render、apply、reload，与刷新时的顺序相同
+    fn refresh(
+        backend: &mut HaproxyBackend,
+        entries: &[Entry],
+        added: &[&str],
+        removed: &[&str],
+    ) -> Result<()> {
+        let preset = strings(&["10.0.0.0/8"]);
+        let (added, removed) = (strings(added), strings(removed));
+        let snapshot = Snapshot {
+            preset: &preset,
+            entries,
+            added: &added,
+            removed: &removed,
+        };
+        let content = backend.render(&snapshot)?;
+        backend.apply(&snapshot, &content)?;
+        backend.reload(&snapshot)
+    }
+
+    #[test]
+    fn full_then_incremental_sync() {
+        let fake = FakeHaproxy::start("haproxy-backend-sync");
+        let mut backend = fake.backend();
+
+        refresh(&mut backend, &entries(&["1.1.1.1"]), &["1.1.1.1"], &[]).unwrap();
+        assert_eq!(
+            fake.take(),
+            ["clear acl F", "add acl F 10.0.0.0/8", "add acl F 1.1.1.1"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(fake.dir.join("wl.acl")).unwrap(),
+            "10.0.0.0/8\n1.1.1.1\n"
+        );
+
+        refresh(
+            &mut backend,
+            &entries(&["2.2.2.2", "3.3.3.0/24"]),
+            &["2.2.2.2", "3.3.3.0/24"],
+            &["1.1.1.1"],
+        )
+        .unwrap();
+        assert_eq!(
+            fake.take(),
+            [
+                "del acl F 1.1.1.1",
+                "add acl F 2.2.2.2",
+                "add acl F 3.3.3.0/24"
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_after_error_response() {
+        let fake = FakeHaproxy::start("haproxy-backend-error");
+        let mut backend = fake.backend();
+        refresh(&mut backend, &entries(&["1.1.1.1"]), &["1.1.1.1"], &[]).unwrap();
+        fake.take();
+
+        *fake.fail.lock().unwrap() = Some(format!(
+            "add acl {} 2.2.2.2",
+            fake.dir.join("wl.acl").display()
+        ));
+        let err = refresh(
+            &mut backend,
+            &entries(&["2.2.2.2"]),
+            &["2.2.2.2"],
+            &["1.1.1.1"],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Unknown ACL"));
+        assert_eq!(fake.take(), ["del acl F 1.1.1.1", "add acl F 2.2.2.2"]);
+
+        *fake.fail.lock().unwrap() = None;
+        refresh(
+            &mut backend,
+            &entries(&["2.2.2.2", "4.4.4.4"]),
+            &["4.4.4.4"],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            fake.take(),
+            [
+                "clear acl F",
+                "add acl F 10.0.0.0/8",
+                "add acl F 2.2.2.2",
+                "add acl F 4.4.4.4"
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_after_write_failure() {
+        let fake = FakeHaproxy::start("haproxy-backend-write");
+        let mut backend = fake.backend();
+        refresh(&mut backend, &entries(&["1.1.1.1"]), &["1.1.1.1"], &[]).unwrap();
+        fake.take();
+
+        // ACL 文件所在位置变成目录，写出失败
+        let acl = fake.dir.join("wl.acl");
+        std::fs::remove_file(&acl).unwrap();
+        std::fs::create_dir(&acl).unwrap();
+        refresh(
+            &mut backend,
+            &entries(&["2.2.2.2"]),
+            &["2.2.2.2"],
+            &["1.1.1.1"],
+        )
+        .unwrap_err();
+        assert!(fake.take().is_empty());
+
+        std::fs::remove_dir(&acl).unwrap();
+        refresh(&mut backend, &entries(&["2.2.2.2"]), &[], &[]).unwrap();
+        assert_eq!(
+            fake.take(),
+            ["clear acl F", "add acl F 10.0.0.0/8", "add acl F 2.2.2.2"]
+        );
+    }
+}
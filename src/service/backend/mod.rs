@@ -0,0 +1,157 @@
+mod apache;
+mod caddy;
+mod haproxy;
+mod nftables;
+mod nginx;
+mod template;
+mod traefik;
+
+pub use apache::{ApacheBackend, ApacheBackendConfig};
+pub use caddy::{CaddyBackend, CaddyBackendConfig};
+pub use haproxy::{HaproxyBackend, HaproxyBackendConfig};
+pub use nftables::{NftablesBackend, NftablesBackendConfig};
+pub use nginx::{NginxBackend, NginxBackendConfig};
+pub use template::{TemplateBackend, TemplateBackendConfig};
+pub use traefik::{TraefikBackend, TraefikBackendConfig};
+
+use crate::Result;
+use std::{
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+/// 白名单中的一项，已按前缀长度转换为 CIDR
+#[derive(Clone)]
+pub struct Entry {
+    pub addr: IpAddr,
+    pub cidr: String,
+    pub expire: SystemTime,
+    pub owner: String,
+}
+
+/// 渲染配置时使用的当前状态
+pub struct Snapshot<'a> {
+    pub preset: &'a [String],
+    pub entries: &'a [Entry],
+    /// 与上次刷新相比新增的 CIDR
+    pub added: &'a [String],
+    /// 与上次刷新相比删除的 CIDR
+    pub removed: &'a [String],
+}
+
+/// 输出后端，负责把白名单应用到具体的代理或防火墙
+///
+/// 每次列表变化时依次调用 `render`、`apply`、`verify`、`reload`，任何一步失败都会中止本次刷新。
+pub trait Backend: Send {
+    fn name(&self) -> &str;
+
+    /// 根据当前状态生成配置内容
+    fn render(&self, snapshot: &Snapshot) -> Result<String>;
+
+    /// 写出配置内容
+    fn apply(&mut self, snapshot: &Snapshot, content: &str) -> Result<()>;
+
+    /// 测试已写出的配置
+    fn verify(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// 使配置生效
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        Ok(())
+    }
+
+    /// 测试或生效失败后，恢复上一次成功的配置，没有可恢复的内容时返回 `false`
+    fn rollback(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// 由后端写出的配置文件，写出前保留上一个版本（`.bak`），失败时可以恢复
+struct ConfigFile {
+    path: PathBuf,
+    backup: PathBuf,
+    has_backup: bool,
+}
+
+impl ConfigFile {
+    fn new(path: &str) -> Self {
+        ConfigFile {
+            path: PathBuf::from(path),
+            backup: PathBuf::from(format!("{}.bak", path)),
+            has_backup: false,
+        }
+    }
+
+    fn write(&mut self, content: &str) -> Result<()> {
+        // 失败的配置总会被恢复，所以当前文件就是上一次成功的配置
+        self.has_backup = self.path.is_file();
+        if self.has_backup {
+            std::fs::copy(&self.path, &self.backup)?;
+        }
+        write_atomic(&self.path, content.as_bytes())
+    }
+
+    fn restore(&self) -> Result<bool> {
+        if !self.has_backup {
+            return Ok(false);
+        }
+        write_atomic(&self.path, &std::fs::read(&self.backup)?)?;
+        Ok(true)
+    }
+}
+
+/// 先写入同目录下的临时文件再改名，保证读取方不会看到写了一半的文件
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("无效的文件路径: {}", path.display()))?;
+    // 以 . 开头，避免被监视目录的程序当作配置文件加载
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// 运行外部命令，等待其退出并检查退出码
+fn run_command(exe: &str, args: &[&str], cwd: Option<&Path>) -> Result<()> {
+    let mut cmd = Command::new(exe);
+    cmd.args(args);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let status = cmd
+        .status()
+        .map_err(|err| format!("创建进程失败: {}", err))?;
+    if !status.success() {
+        return Err(status.to_string().into());
+    }
+    Ok(())
+}
+
+/// 运行外部命令，并把 `input` 写入其标准输入
+fn run_command_with_input(exe: &str, args: &[&str], input: &str) -> Result<()> {
+    let mut p = Command::new(exe)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("创建进程失败: {}", err))?;
+    // 写完后关闭标准输入，否则子进程会一直等待
+    let write_result = p.stdin.take().unwrap().write_all(input.as_bytes());
+    let status = p.wait()?;
+    write_result?;
+    if !status.success() {
+        return Err(status.to_string().into());
+    }
+    Ok(())
+}
+
+/// 可执行文件所在目录，只给了文件名时返回 `None`
+fn exe_dir(exe: &str) -> Option<&Path> {
+    Path::new(exe)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+}
@@ -0,0 +1,299 @@
+use super::{run_command_with_input, Backend, Snapshot};
+use crate::Result;
+use ipnet::IpNet;
+use std::{cmp::Reverse, net::IpAddr, time::SystemTime};
+
+#[derive(Clone)]
+pub struct NftablesBackendConfig {
+    pub exe: String,
+    pub family: String,
+    pub table: String,
+    pub set4: String,
+    pub set6: String,
+}
+
+/// 把白名单同步到 nftables 的命名集合中，每个元素带有根据过期时间计算的超时
+///
+/// 集合需要事先创建，并带有 `flags timeout`，如果白名单中包含网段（预置项或设置了前缀长度），还需要 `flags interval`。
+/// 每次刷新时先清空集合再重新添加，在同一个事务中完成，不会出现短暂的空集合。
+/// 区间集合不允许元素重叠，被预置项或更大的网段包含的元素不会添加。
+pub struct NftablesBackend {
+    config: NftablesBackendConfig,
+    script: String,
+}
+
+impl NftablesBackend {
+    pub fn new(config: NftablesBackendConfig) -> Self {
+        NftablesBackend {
+            config,
+            script: String::new(),
+        }
+    }
+
+    fn render_set(&self, s: &mut String, set: &str, elements: &[String]) {
+        if set.is_empty() {
+            return;
+        }
+        s.push_str(&format!(
+            "flush set {} {} {}\n",
+            self.config.family, self.config.table, set
+        ));
+        if !elements.is_empty() {
+            s.push_str(&format!(
+                "add element {} {} {} {{ {} }}\n",
+                self.config.family,
+                self.config.table,
+                set,
+                elements.join(", ")
+            ));
+        }
+    }
+}
+
+impl Backend for NftablesBackend {
+    fn name(&self) -> &str {
+        "nftables"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        // 预置项没有超时
+        let mut elements: Vec<(IpNet, Option<u64>)> = vec![];
+        for i in snapshot.preset {
+            match parse_net(i) {
+                Some(net) => elements.push((net, None)),
+                None => warn!("[nftables] 忽略无效的预置项: {}", i),
+            }
+        }
+        let now = SystemTime::now();
+        for i in snapshot.entries {
+            let Some(net) = parse_net(&i.cidr) else {
+                return Err(format!("无效的地址: {}", i.cidr).into());
+            };
+            // 至少保留 1 秒，timeout 为 0 会被 nft 拒绝
+            let timeout = i
+                .expire
+                .duration_since(now)
+                .unwrap_or_default()
+                .as_secs()
+                .max(1);
+            elements.push((net, Some(timeout)));
+        }
+        let mut v4 = vec![];
+        let mut v6 = vec![];
+        for (net, timeout) in remove_overlaps(elements) {
+            let element = match timeout {
+                Some(timeout) => format!("{} timeout {}s", net_to_string(&net), timeout),
+                None => net_to_string(&net),
+            };
+            match net {
+                IpNet::V4(_) => v4.push(element),
+                IpNet::V6(_) => v6.push(element),
+            }
+        }
+        let mut s = String::new();
+        self.render_set(&mut s, &self.config.set4, &v4);
+        self.render_set(&mut s, &self.config.set6, &v6);
+        Ok(s)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.script = content.to_string();
+        Ok(())
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        run_command_with_input(&self.config.exe, &["-c", "-f", "-"], &self.script)
+    }
+
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        run_command_with_input(&self.config.exe, &["-f", "-"], &self.script)
+    }
+}
+
+/// 解析 `1.2.3.4` 或 `1.2.3.0/24`
+fn parse_net(s: &str) -> Option<IpNet> {
+    s.parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+/// 单个地址不带前缀长度
+fn net_to_string(net: &IpNet) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+/// 去掉被其他元素包含的元素，保持原来的顺序
+///
+/// 从大网段开始保留，相同网段优先保留预置项和超时更长的。被包含的元素过期前，包含它的元素
+/// 过期时列表会变化并重新生成，所以不会提前失效。
+fn remove_overlaps(elements: Vec<(IpNet, Option<u64>)>) -> Vec<(IpNet, Option<u64>)> {
+    let mut order: Vec<usize> = (0..elements.len()).collect();
+    order.sort_by_key(|&i| {
+        let (net, timeout) = elements[i];
+        (net.prefix_len(), Reverse(timeout.unwrap_or(u64::MAX)))
+    });
+    let mut keep = vec![false; elements.len()];
+    let mut kept: Vec<IpNet> = vec![];
+    for i in order {
+        let net = elements[i].0;
+        if !kept.iter().any(|k| k.contains(&net)) {
+            kept.push(net);
+            keep[i] = true;
+        }
+    }
+    elements
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(e, keep)| keep.then_some(e))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::backend::Entry;
+    use std::time::Duration;
+
+    /// 代替 nft 的脚本，把每次调用的参数和标准输入追加到 `log` 中
+    #[cfg(unix)]
+    fn stub(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("nft");
+        let log = dir.join("log");
+        let _ = std::fs::remove_file(&log);
+        std::fs::write(
+            &exe,
+            format!(
+                "#!/bin/sh\necho \"args: $*\" >> '{0}'\ncat >> '{0}'\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (exe, log)
+    }
+
+    fn entry(cidr: &str, expire: SystemTime) -> Entry {
+        Entry {
+            addr: cidr.split('/').next().unwrap().parse().unwrap(),
+            cidr: cidr.to_string(),
+            expire,
+            owner: String::new(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn flush_and_add_with_timeout() {
+        let (exe, log) = stub("nftables-backend-test");
+        let mut backend = NftablesBackend::new(NftablesBackendConfig {
+            exe: exe.to_string_lossy().into_owned(),
+            family: "inet".to_string(),
+            table: "filter".to_string(),
+            set4: "wl4".to_string(),
+            set6: "wl6".to_string(),
+        });
+        let now = SystemTime::now();
+        let preset = vec!["10.0.0.0/8".to_string()];
+        let entries = vec![
+            // 多留半秒，避免渲染时已经不足 100 秒
+            entry("1.2.3.4", now + Duration::from_millis(100_500)),
+            entry("1.2.4.0/24", now - Duration::from_secs(5)),
+        ];
+        let snapshot = Snapshot {
+            preset: &preset,
+            entries: &entries,
+            added: &[],
+            removed: &[],
+        };
+        let script = backend.render(&snapshot).unwrap();
+        assert_eq!(
+            script,
+            "flush set inet filter wl4\n\
+             add element inet filter wl4 { 10.0.0.0/8, 1.2.3.4 timeout 100s, 1.2.4.0/24 timeout 1s }\n\
+             flush set inet filter wl6\n"
+        );
+
+        backend.apply(&snapshot, &script).unwrap();
+        backend.verify().unwrap();
+        backend.reload(&snapshot).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            format!("args: -c -f -\n{0}args: -f -\n{0}", script)
+        );
+        let _ = std::fs::remove_dir_all(exe.parent().unwrap());
+    }
+
+    #[test]
+    fn remove_overlapping_elements() {
+        let backend = NftablesBackend::new(NftablesBackendConfig {
+            exe: "nft".to_string(),
+            family: "inet".to_string(),
+            table: "filter".to_string(),
+            set4: "wl4".to_string(),
+            set6: "wl6".to_string(),
+        });
+        let now = SystemTime::now() + Duration::from_millis(500);
+        let preset = vec![
+            "127.0.0.0/8".to_string(),
+            "192.168.1.1".to_string(),
+            "127.0.0.1".to_string(),
+        ];
+        let entries = vec![
+            // 被预置项包含
+            entry("127.0.0.1", now + Duration::from_secs(100)),
+            entry("192.168.1.1", now + Duration::from_secs(100)),
+            // 被更大的网段包含
+            entry("10.1.2.0/24", now + Duration::from_secs(300)),
+            entry("10.0.0.0/8", now + Duration::from_secs(200)),
+            // 相同的网段只保留超时更长的
+            entry("2001:db8::/64", now + Duration::from_secs(100)),
+            entry("2001:db8::/64", now + Duration::from_secs(200)),
+            entry("10.1.2.3", now + Duration::from_secs(100)),
+            entry("172.16.0.1", now + Duration::from_secs(100)),
+        ];
+        let snapshot = Snapshot {
+            preset: &preset,
+            entries: &entries,
+            added: &[],
+            removed: &[],
+        };
+        assert_eq!(
+            backend.render(&snapshot).unwrap(),
+            "flush set inet filter wl4\n\
+             add element inet filter wl4 { 127.0.0.0/8, 192.168.1.1, 10.0.0.0/8 timeout 200s, 172.16.0.1 timeout 100s }\n\
+             flush set inet filter wl6\n\
+             add element inet filter wl6 { 2001:db8::/64 timeout 200s }\n"
+        );
+    }
+
+    #[test]
+    fn skip_empty_set_name() {
+        let backend = NftablesBackend::new(NftablesBackendConfig {
+            exe: "nft".to_string(),
+            family: "ip6".to_string(),
+            table: "t".to_string(),
+            set4: String::new(),
+            set6: "wl6".to_string(),
+        });
+        let preset = vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()];
+        let snapshot = Snapshot {
+            preset: &preset,
+            entries: &[],
+            added: &[],
+            removed: &[],
+        };
+        let script = backend.render(&snapshot).unwrap();
+        assert_eq!(
+            script,
+            "flush set ip6 t wl6\nadd element ip6 t wl6 { 2001:db8::/32 }\n"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use super::{exe_dir, run_command, Backend, ConfigFile, Snapshot};
+use crate::Result;
+
+#[derive(Clone)]
+pub struct NginxBackendConfig {
+    pub conf: String,
+    pub exe: String,
+    pub remote_addr_var: String,
+    pub result_var: String,
+}
+
+/// 生成 nginx 的 geo 配置，通过 `nginx -t` 测试，`nginx -s reload` 刷新
+pub struct NginxBackend {
+    config: NginxBackendConfig,
+    file: ConfigFile,
+}
+
+impl NginxBackend {
+    pub fn new(config: NginxBackendConfig) -> Self {
+        NginxBackend {
+            file: ConfigFile::new(&config.conf),
+            config,
+        }
+    }
+}
+
+impl Backend for NginxBackend {
+    fn name(&self) -> &str {
+        "nginx"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        let mut s = String::new();
+        s.push_str(&format!(
+            "geo ${} ${} {{\n",
+            self.config.remote_addr_var, self.config.result_var
+        ));
+        s.push_str("default 0;\n");
+        for i in snapshot.preset {
+            s.push_str(&format!("{} 1;\n", i));
+        }
+        for i in snapshot.entries {
+            s.push_str(&format!("{} 1;\n", i.cidr));
+        }
+        s.push_str("}\n");
+        Ok(s)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.file.write(content)
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        run_command(&self.config.exe, &["-t"], exe_dir(&self.config.exe))
+    }
+
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        run_command(
+            &self.config.exe,
+            &["-s", "reload"],
+            exe_dir(&self.config.exe),
+        )
+    }
+
+    fn rollback(&mut self) -> Result<bool> {
+        self.file.restore()
+    }
+}
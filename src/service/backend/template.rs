@@ -0,0 +1,120 @@
+use super::{exe_dir, run_command, Backend, ConfigFile, Snapshot};
+use crate::Result;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone)]
+pub struct TemplateBackendConfig {
+    pub name: String,
+    pub template: String,
+    pub output: String,
+    pub validate: Vec<Vec<String>>,
+    pub reload: Vec<Vec<String>>,
+}
+
+/// 使用 minijinja 模板生成任意格式的配置，并依次执行配置的测试、刷新命令
+///
+/// 模板中可用的变量：
+///
+/// - `entries`：全部白名单项，包括预置项，每项含 `address`、`cidr`、`expire`（unix 时间戳，预置项为 0）、
+///   `ttl`（剩余秒数，预置项为 0）、`owner`（添加该项的用户，预置项为空）、`group`（`preset` 或 `dynamic`）
+/// - `preset`：预置的白名单
+pub struct TemplateBackend {
+    config: TemplateBackendConfig,
+    file: ConfigFile,
+}
+
+#[derive(Serialize)]
+struct TemplateEntry<'a> {
+    address: String,
+    cidr: &'a str,
+    expire: u64,
+    ttl: u64,
+    owner: &'a str,
+    group: &'static str,
+}
+
+impl TemplateBackend {
+    pub fn new(config: TemplateBackendConfig) -> Self {
+        TemplateBackend {
+            file: ConfigFile::new(&config.output),
+            config,
+        }
+    }
+}
+
+impl Backend for TemplateBackend {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        // 每次都重新读取模板，修改模板后不需要重启
+        let source = std::fs::read_to_string(&self.config.template)?;
+        let mut env = minijinja::Environment::new();
+        env.set_keep_trailing_newline(true);
+        env.add_template("main", &source)?;
+
+        let now = SystemTime::now();
+        let mut entries: Vec<TemplateEntry> = snapshot
+            .preset
+            .iter()
+            .map(|i| TemplateEntry {
+                address: i.split('/').next().unwrap_or(i).to_string(),
+                cidr: i,
+                expire: 0,
+                ttl: 0,
+                owner: "",
+                group: "preset",
+            })
+            .collect();
+        entries.extend(snapshot.entries.iter().map(|i| {
+            TemplateEntry {
+                address: i.addr.to_string(),
+                cidr: &i.cidr,
+                expire: i
+                    .expire
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                ttl: i.expire.duration_since(now).unwrap_or_default().as_secs(),
+                owner: &i.owner,
+                group: "dynamic",
+            }
+        }));
+        let content = env.get_template("main")?.render(minijinja::context! {
+            entries => entries,
+            preset => snapshot.preset,
+        })?;
+        Ok(content)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.file.write(content)
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        run_commands(&self.config.validate)
+    }
+
+    fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        run_commands(&self.config.reload)
+    }
+
+    fn rollback(&mut self) -> Result<bool> {
+        self.file.restore()
+    }
+}
+
+/// 依次执行命令，每条命令为 `[程序, 参数...]`，工作目录为程序所在目录
+fn run_commands(commands: &[Vec<String>]) -> Result<()> {
+    for cmd in commands {
+        let Some((exe, args)) = cmd.split_first() else {
+            continue;
+        };
+        let args: Vec<&str> = args.iter().map(|i| i.as_str()).collect();
+        run_command(exe, &args, exe_dir(exe))
+            .map_err(|err| format!("{}: {}", cmd.join(" "), err))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use super::{Backend, ConfigFile, Snapshot};
+use crate::Result;
+
+/// RFC 5737 文档保留地址，不会有真实的客户端
+const DENY_ALL: &str = "192.0.2.0/32";
+
+#[derive(Clone)]
+pub struct TraefikBackendConfig {
+    pub path: String,
+    pub middleware: String,
+}
+
+/// 生成 Traefik 文件提供者的动态配置，定义一个 `ipAllowList` 中间件
+///
+/// 根据文件扩展名输出 TOML 或 YAML，原子地替换文件，由 Traefik 监视文件变化自动加载。
+pub struct TraefikBackend {
+    config: TraefikBackendConfig,
+    file: ConfigFile,
+}
+
+impl TraefikBackend {
+    pub fn new(config: TraefikBackendConfig) -> Self {
+        TraefikBackend {
+            file: ConfigFile::new(&config.path),
+            config,
+        }
+    }
+}
+
+impl Backend for TraefikBackend {
+    fn name(&self) -> &str {
+        "traefik"
+    }
+
+    fn render(&self, snapshot: &Snapshot) -> Result<String> {
+        let mut ranges: Vec<String> = snapshot
+            .preset
+            .iter()
+            .map(|i| format!("\"{}\"", i))
+            .chain(snapshot.entries.iter().map(|i| format!("\"{}\"", i.cidr)))
+            .collect();
+        // sourceRange 为空时 Traefik 无法创建中间件，使用了它的路由会被丢弃，
+        // 这里用一个不会出现的文档保留地址代替，拒绝所有请求
+        if ranges.is_empty() {
+            ranges.push(format!("\"{}\"", DENY_ALL));
+        }
+        let mut s = String::new();
+        if self.config.path.ends_with(".toml") {
+            s.push_str(&format!(
+                "[http.middlewares.{}.ipAllowList]\n",
+                self.config.middleware
+            ));
+            s.push_str(&format!("  sourceRange = [{}]\n", ranges.join(", ")));
+        } else {
+            s.push_str("http:\n");
+            s.push_str("  middlewares:\n");
+            s.push_str(&format!("    {}:\n", self.config.middleware));
+            s.push_str("      ipAllowList:\n");
+            s.push_str("        sourceRange:\n");
+            for i in ranges {
+                s.push_str(&format!("          - {}\n", i));
+            }
+        }
+        Ok(s)
+    }
+
+    fn apply(&mut self, _snapshot: &Snapshot, content: &str) -> Result<()> {
+        self.file.write(content)
+    }
+
+    fn rollback(&mut self) -> Result<bool> {
+        self.file.restore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(path: &str, preset: &[&str]) -> String {
+        let backend = TraefikBackend::new(TraefikBackendConfig {
+            path: path.to_string(),
+            middleware: "wl".to_string(),
+        });
+        let preset: Vec<String> = preset.iter().map(|i| i.to_string()).collect();
+        backend
+            .render(&Snapshot {
+                preset: &preset,
+                entries: &[],
+                added: &[],
+                removed: &[],
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn empty_list_denies_all() {
+        assert_eq!(
+            render("/tmp/wl.yml", &[]),
+            "http:\n  middlewares:\n    wl:\n      ipAllowList:\n        sourceRange:\n          - \"192.0.2.0/32\"\n"
+        );
+        assert_eq!(
+            render("/tmp/wl.toml", &[]),
+            "[http.middlewares.wl.ipAllowList]\n  sourceRange = [\"192.0.2.0/32\"]\n"
+        );
+    }
+
+    #[test]
+    fn preset_ranges() {
+        assert_eq!(
+            render("/tmp/wl.toml", &["10.0.0.0/8", "::1"]),
+            "[http.middlewares.wl.ipAllowList]\n  sourceRange = [\"10.0.0.0/8\", \"::1\"]\n"
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use super::{read_state, write_state};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone)]
+pub struct LockoutServiceConfig {
+    pub max_failures: u32,
+    pub prefix_max_failures: u32,
+    pub ipv4_prefixlen: u8,
+    pub ipv6_prefixlen: u8,
+    pub window: Duration,
+    pub ban_time: Duration,
+    pub max_ban_time: Duration,
+    pub state_file: String,
+}
+
+/// 记录每个 IP 及其所在网段的失败次数，超过阈值后临时封禁，重复封禁时时长加倍
+pub struct LockoutService {
+    config: LockoutServiceConfig,
+    records: Mutex<HashMap<IpNet, Record>>,
+}
+
+struct Record {
+    failures: u32,
+    first_failure: SystemTime,
+    /// 已被封禁的次数，用于计算下次封禁的时长
+    bans: u32,
+    banned_until: SystemTime,
+}
+
+impl Record {
+    fn new(now: SystemTime) -> Self {
+        Record {
+            failures: 0,
+            first_failure: now,
+            bans: 0,
+            banned_until: UNIX_EPOCH,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateEntry {
+    net: String,
+    bans: u32,
+    /// 封禁结束时间，unix 时间戳（秒）
+    banned_until: u64,
+}
+
+impl LockoutService {
+    pub fn new(config: LockoutServiceConfig) -> Self {
+        let svc = LockoutService {
+            config,
+            records: Mutex::new(HashMap::new()),
+        };
+        svc.load_state();
+        svc
+    }
+
+    /// 返回剩余的封禁时间，未被封禁时返回 `None`
+    pub fn check(&self, ip: &IpAddr) -> Option<Duration> {
+        let now = SystemTime::now();
+        let records = self.records.lock().unwrap();
+        self.keys(ip)
+            .iter()
+            .filter_map(|(net, _)| records.get(net))
+            .filter_map(|r| r.banned_until.duration_since(now).ok())
+            .max()
+    }
+
+    /// 记录一次失败，达到阈值时封禁
+    pub fn failure(&self, ip: &IpAddr) {
+        let now = SystemTime::now();
+        let mut banned = false;
+        {
+            let mut records = self.records.lock().unwrap();
+            self.cleanup(&mut records, now);
+            for (net, threshold) in self.keys(ip) {
+                if threshold == 0 {
+                    continue;
+                }
+                let r = records.entry(net).or_insert_with(|| Record::new(now));
+                if r.failures == 0
+                    || now.duration_since(r.first_failure).unwrap_or_default() > self.config.window
+                {
+                    r.failures = 0;
+                    r.first_failure = now;
+                }
+                r.failures += 1;
+                if r.failures >= threshold {
+                    // 封禁时长按 2 的次方增长，最长不超过 max_ban_time
+                    let factor = 2u32.saturating_pow(r.bans);
+                    let time = self
+                        .config
+                        .ban_time
+                        .saturating_mul(factor)
+                        .min(self.config.max_ban_time);
+                    r.bans += 1;
+                    r.failures = 0;
+                    r.banned_until = now + time;
+                    warn!("{} 失败次数过多，封禁 {} 秒", net, time.as_secs());
+                    banned = true;
+                }
+            }
+        }
+        if banned {
+            self.save_state();
+        }
+    }
+
+    /// 成功后清除该 IP 的失败记录，网段的记录保留
+    pub fn success(&self, ip: &IpAddr) {
+        let net = IpNet::from(ip.to_canonical());
+        let mut records = self.records.lock().unwrap();
+        if let Some(r) = records.get_mut(&net) {
+            r.failures = 0;
+        }
+    }
+
+    fn keys(&self, ip: &IpAddr) -> Vec<(IpNet, u32)> {
+        let ip = ip.to_canonical();
+        let prefixlen = match ip {
+            IpAddr::V4(_) => self.config.ipv4_prefixlen,
+            IpAddr::V6(_) => self.config.ipv6_prefixlen,
+        };
+        let mut keys = vec![(IpNet::from(ip), self.config.max_failures)];
+        if let Ok(net) = IpNet::new(ip, prefixlen) {
+            if prefixlen != 0 && net.prefix_len() != net.max_prefix_len() {
+                keys.push((net.trunc(), self.config.prefix_max_failures));
+            }
+        }
+        keys
+    }
+
+    /// 删除既不在封禁中、失败计数也已过期的记录，封禁次数在封禁结束 max_ban_time 后清零
+    fn cleanup(&self, records: &mut HashMap<IpNet, Record>, now: SystemTime) {
+        records.retain(|_, r| {
+            let banned = r.banned_until + self.config.max_ban_time > now;
+            let counting = r.failures > 0
+                && now.duration_since(r.first_failure).unwrap_or_default() <= self.config.window;
+            banned || counting
+        });
+    }
+
+    fn load_state(&self) {
+        if self.config.state_file.is_empty() {
+            return;
+        }
+        let path = std::path::Path::new(&self.config.state_file);
+        if !path.is_file() {
+            return;
+        }
+        let entries: Vec<StateEntry> = match read_state(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("读取封禁状态文件失败: {}", err);
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        let mut records = self.records.lock().unwrap();
+        for entry in entries {
+            let Ok(net) = entry.net.parse::<IpNet>() else {
+                continue;
+            };
+            let mut r = Record::new(now);
+            r.bans = entry.bans;
+            r.banned_until = UNIX_EPOCH + Duration::from_secs(entry.banned_until);
+            records.insert(net, r);
+        }
+        self.cleanup(&mut records, now);
+        info!("已从封禁状态文件恢复 {} 条记录", records.len());
+    }
+
+    fn save_state(&self) {
+        if self.config.state_file.is_empty() {
+            return;
+        }
+        let entries: Vec<StateEntry> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, r)| r.bans > 0)
+            .map(|(net, r)| StateEntry {
+                net: net.to_string(),
+                bans: r.bans,
+                banned_until: r
+                    .banned_until
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+        if let Err(err) = write_state(std::path::Path::new(&self.config.state_file), &entries) {
+            error!("写出封禁状态文件失败: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(prefix_max_failures: u32) -> LockoutServiceConfig {
+        LockoutServiceConfig {
+            max_failures: 3,
+            prefix_max_failures,
+            ipv4_prefixlen: 24,
+            ipv6_prefixlen: 64,
+            window: Duration::from_secs(60),
+            ban_time: Duration::from_secs(10),
+            max_ban_time: Duration::from_secs(40),
+            state_file: String::new(),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// 剩余的封禁秒数，向上取整
+    fn banned_secs(svc: &LockoutService, ip: &IpAddr) -> Option<u64> {
+        svc.check(ip)
+            .map(|d| (d + Duration::from_millis(999)).as_secs())
+    }
+
+    /// 把所有记录的时间往前移，模拟时间流逝
+    fn elapse(svc: &LockoutService, d: Duration) {
+        for r in svc.records.lock().unwrap().values_mut() {
+            r.first_failure -= d;
+            r.banned_until = r.banned_until.checked_sub(d).unwrap_or(UNIX_EPOCH);
+        }
+    }
+
+    #[test]
+    fn ban_after_max_failures() {
+        let svc = LockoutService::new(config(0));
+        let a = ip("1.2.3.4");
+        svc.failure(&a);
+        svc.failure(&a);
+        assert_eq!(svc.check(&a), None);
+        svc.failure(&a);
+        assert_eq!(banned_secs(&svc, &a), Some(10));
+        // IPv4 映射地址按 IPv4 计算
+        assert_eq!(banned_secs(&svc, &ip("::ffff:1.2.3.4")), Some(10));
+        assert_eq!(svc.check(&ip("1.2.3.5")), None);
+    }
+
+    #[test]
+    fn success_resets_ip_failures() {
+        let svc = LockoutService::new(config(0));
+        let a = ip("1.2.3.4");
+        svc.failure(&a);
+        svc.failure(&a);
+        svc.success(&a);
+        svc.failure(&a);
+        svc.failure(&a);
+        assert_eq!(svc.check(&a), None);
+    }
+
+    #[test]
+    fn failures_expire_after_window() {
+        let svc = LockoutService::new(config(0));
+        let a = ip("1.2.3.4");
+        svc.failure(&a);
+        svc.failure(&a);
+        elapse(&svc, Duration::from_secs(61));
+        svc.failure(&a);
+        assert_eq!(svc.check(&a), None);
+        assert_eq!(svc.records.lock().unwrap()[&IpNet::from(a)].failures, 1);
+
+        // 过期的记录在下次失败时清理
+        elapse(&svc, Duration::from_secs(61));
+        svc.failure(&ip("5.6.7.8"));
+        let records = svc.records.lock().unwrap();
+        assert!(!records.contains_key(&IpNet::from(a)));
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn ban_prefix() {
+        let svc = LockoutService::new(config(5));
+        for i in 1..=4 {
+            svc.failure(&ip(&format!("1.2.3.{}", i)));
+        }
+        assert_eq!(svc.check(&ip("1.2.3.200")), None);
+        // 成功只清除单个 IP 的计数，不影响网段
+        svc.success(&ip("1.2.3.1"));
+        svc.failure(&ip("1.2.3.5"));
+        assert_eq!(banned_secs(&svc, &ip("1.2.3.200")), Some(10));
+        assert_eq!(svc.check(&ip("1.2.4.1")), None);
+
+        for i in 1..=5 {
+            svc.failure(&ip(&format!("2001:db8::{}", i)));
+        }
+        assert_eq!(banned_secs(&svc, &ip("2001:db8::ffff")), Some(10));
+        assert_eq!(svc.check(&ip("2001:db8:0:1::1")), None);
+    }
+
+    #[test]
+    fn ban_time_doubles_up_to_max() {
+        let svc = LockoutService::new(config(0));
+        let a = ip("1.2.3.4");
+        for expected in [10, 20, 40, 40] {
+            for _ in 0..3 {
+                svc.failure(&a);
+            }
+            assert_eq!(banned_secs(&svc, &a), Some(expected));
+            elapse(&svc, Duration::from_secs(expected));
+            assert_eq!(svc.check(&a), None);
+        }
+
+        // 封禁结束 max_ban_time 之后，封禁次数清零
+        elapse(&svc, Duration::from_secs(41));
+        for _ in 0..3 {
+            svc.failure(&a);
+        }
+        assert_eq!(banned_secs(&svc, &a), Some(10));
+    }
+
+    #[test]
+    fn restore_from_state_file() {
+        let dir = std::env::temp_dir().join(format!("lockout-state-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.bans");
+        let _ = std::fs::remove_file(&path);
+        let mut cfg = config(0);
+        cfg.state_file = path.to_string_lossy().into_owned();
+
+        let a = ip("1.2.3.4");
+        let svc = LockoutService::new(cfg.clone());
+        for _ in 0..6 {
+            svc.failure(&a);
+        }
+        assert_eq!(banned_secs(&svc, &a), Some(20));
+        // 临时文件以 . 开头，不会与同名不同扩展名的文件冲突
+        assert!(!dir.join("state.tmp").exists());
+
+        let svc = LockoutService::new(cfg.clone());
+        assert!(banned_secs(&svc, &a).is_some_and(|s| (19..=20).contains(&s)));
+        // 封禁次数也被恢复，下次封禁时长继续加倍
+        elapse(&svc, Duration::from_secs(20));
+        for _ in 0..3 {
+            svc.failure(&a);
+        }
+        assert_eq!(banned_secs(&svc, &a), Some(40));
+
+        std::fs::write(&path, "not json").unwrap();
+        let svc = LockoutService::new(cfg);
+        assert_eq!(svc.check(&a), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,7 +1,32 @@
 mod whitelistservice;
 mod baidulocationservice;
 mod messageservice;
+mod backend;
+mod lockoutservice;
+mod ratelimitservice;
 
-pub use whitelistservice::{WhiteListService, WhiteListServiceConfig};
+pub use whitelistservice::{EntryInfo, IpStatus, WhiteListService, WhiteListServiceConfig};
 pub use baidulocationservice::{BaiduLocationService, BaiduLocationServiceConfig};
 pub use messageservice::{MessageService, MessageServiceConfig};
+pub use backend::{
+    ApacheBackend, ApacheBackendConfig, Backend, CaddyBackend, CaddyBackendConfig, HaproxyBackend,
+    HaproxyBackendConfig, NftablesBackend, NftablesBackendConfig, NginxBackend, NginxBackendConfig,
+    TemplateBackend, TemplateBackendConfig, TraefikBackend, TraefikBackendConfig,
+};
+pub use lockoutservice::{LockoutService, LockoutServiceConfig};
+pub use ratelimitservice::{RateClass, RateLimit, RateLimitService, RateLimitServiceConfig};
+
+use crate::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+
+/// 读取 json 格式的状态文件
+fn read_state<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// 写出 json 格式的状态文件，先写临时文件再改名，避免写到一半时退出导致状态文件损坏
+fn write_state<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    backend::write_atomic(path, &serde_json::to_vec(state)?)
+}
@@ -0,0 +1,179 @@
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// 令牌桶参数，`rate` 为每秒补充的令牌数，为 0 时不限制
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Clone)]
+pub struct RateLimitServiceConfig {
+    pub get: RateLimit,
+    pub post: RateLimit,
+    pub api: RateLimit,
+    pub global: RateLimit,
+}
+
+/// 请求的类别，各自有独立的令牌桶
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RateClass {
+    Get,
+    Post,
+    Api,
+}
+
+/// 按客户端 IP 和请求类别限速，另有一个所有请求共享的全局令牌桶
+pub struct RateLimitService {
+    config: RateLimitServiceConfig,
+    buckets: Mutex<lru_time_cache::LruCache<(IpAddr, RateClass), Bucket>>,
+    global: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// 取一个令牌，不足时返回需要等待的时间
+    fn take(&mut self, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.rate))
+        }
+    }
+}
+
+impl RateLimitService {
+    pub fn new(config: RateLimitServiceConfig) -> Self {
+        RateLimitService {
+            global: Mutex::new(Bucket::new(&config.global)),
+            config,
+            // 空闲的令牌桶早已补满，过期后删除不影响结果
+            buckets: Mutex::new(lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(10 * 60),
+                10000,
+            )),
+        }
+    }
+
+    /// 允许请求时返回 `Ok`，否则返回建议的重试等待时间
+    pub fn check(&self, ip: &IpAddr, class: RateClass) -> Result<(), Duration> {
+        let limit = match class {
+            RateClass::Get => &self.config.get,
+            RateClass::Post => &self.config.post,
+            RateClass::Api => &self.config.api,
+        };
+        if limit.rate > 0.0 {
+            let mut buckets = self.buckets.lock().unwrap();
+            buckets
+                .entry((*ip, class))
+                .or_insert_with(|| Bucket::new(limit))
+                .take(limit)?;
+        }
+        if self.config.global.rate > 0.0 {
+            self.global.lock().unwrap().take(&self.config.global)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNLIMITED: RateLimit = RateLimit {
+        rate: 0.0,
+        burst: 0,
+    };
+
+    fn limit(rate: f64, burst: u32) -> RateLimit {
+        RateLimit { rate, burst }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_then_retry_after() {
+        let limit = limit(0.5, 3);
+        let mut bucket = Bucket::new(&limit);
+        for _ in 0..3 {
+            assert!(bucket.take(&limit).is_ok());
+        }
+        // 每秒补充 0.5 个，需要约 2 秒才能补充一个
+        let wait = bucket.take(&limit).unwrap_err();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn refill_up_to_burst() {
+        let limit = limit(2.0, 3);
+        let mut bucket = Bucket::new(&limit);
+        for _ in 0..3 {
+            bucket.take(&limit).unwrap();
+        }
+        bucket.last -= Duration::from_secs(1);
+        assert!(bucket.take(&limit).is_ok());
+        assert!(bucket.take(&limit).is_ok());
+        assert!(bucket.take(&limit).is_err());
+
+        // 空闲很久也只能连续发出 burst 个请求
+        bucket.last -= Duration::from_secs(100);
+        for _ in 0..3 {
+            assert!(bucket.take(&limit).is_ok());
+        }
+        assert!(bucket.take(&limit).is_err());
+    }
+
+    #[test]
+    fn per_ip_and_class() {
+        let svc = RateLimitService::new(RateLimitServiceConfig {
+            get: limit(0.1, 1),
+            post: limit(0.1, 1),
+            api: UNLIMITED,
+            global: UNLIMITED,
+        });
+        let a = ip("1.2.3.4");
+        assert!(svc.check(&a, RateClass::Get).is_ok());
+        assert!(svc.check(&a, RateClass::Get).is_err());
+        assert!(svc.check(&a, RateClass::Post).is_ok());
+        assert!(svc.check(&ip("1.2.3.5"), RateClass::Get).is_ok());
+        for _ in 0..100 {
+            assert!(svc.check(&a, RateClass::Api).is_ok());
+        }
+    }
+
+    #[test]
+    fn global_bucket_is_shared() {
+        let svc = RateLimitService::new(RateLimitServiceConfig {
+            get: UNLIMITED,
+            post: UNLIMITED,
+            api: UNLIMITED,
+            global: limit(1.0, 2),
+        });
+        assert!(svc.check(&ip("1.2.3.4"), RateClass::Get).is_ok());
+        assert!(svc.check(&ip("5.6.7.8"), RateClass::Post).is_ok());
+        let wait = svc.check(&ip("9.9.9.9"), RateClass::Api).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+    }
+}
@@ -1,142 +1,662 @@
 #![allow(dead_code)]
 
-use super::{BaiduLocationService, MessageService};
+use super::{
+    backend::{Backend, Entry, Snapshot},
+    read_state, write_state, BaiduLocationService, MessageService,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    future::Future,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    sync::mpsc,
-    time::{Duration, Instant},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{JoinError, JoinHandle},
 };
 
 #[derive(Clone)]
 pub struct WhiteListServiceConfig {
-    pub nginx_conf: String,
-    pub nginx_exe: String,
-    pub remote_addr_var: String,
-    pub result_var: String,
     pub timeout: Duration,
     pub loop_delay: Duration,
     pub ipv4_prefixlen: u8,
     pub ipv6_prefixlen: u8,
     pub preset: Vec<String>,
+    pub state_file: String,
+    /// 有效的用户，从状态文件恢复时丢弃其他用户添加的记录
+    pub owners: Vec<String>,
+    /// 滑动续期：`auth_request`、状态页面的访问和主动续期会把过期时间推后
+    pub sliding: bool,
 }
 
 enum Message {
-    Push(IpAddr),
-    Terminate,
+    Push(IpAddr, String, Duration),
+    Check(IpAddr, oneshot::Sender<bool>),
+    RetainOwners(Vec<String>),
+    List(oneshot::Sender<Vec<EntryInfo>>),
+    Add(AddRequest, oneshot::Sender<Option<EntryInfo>>),
+    Remove(IpNet, oneshot::Sender<usize>),
+    Extend(IpNet, Option<Duration>, oneshot::Sender<Vec<EntryInfo>>),
+    Logout(IpAddr, bool, oneshot::Sender<Vec<EntryInfo>>),
+    Status(IpAddr, oneshot::Sender<IpStatus>),
+    Renew(IpAddr, oneshot::Sender<Vec<EntryInfo>>),
+    /// 保存状态后退出，保存完成后通知调用方
+    Terminate(Option<oneshot::Sender<()>>),
+}
+
+struct AddRequest {
+    addr: IpAddr,
+    prefixlen: Option<u8>,
+    ttl: Option<Duration>,
+    owner: String,
+}
+
+/// 白名单中的一项，供接口查询
+pub struct EntryInfo {
+    pub address: IpAddr,
+    pub cidr: String,
+    pub owner: String,
+    pub created: SystemTime,
+    pub expire: SystemTime,
+}
+
+/// 某个 IP 当前的白名单状态
+#[derive(Default)]
+pub struct IpStatus {
+    /// 在预置的白名单中
+    pub preset: bool,
+    /// 包含该 IP 的有效项
+    pub entries: Vec<EntryInfo>,
+    /// 各后端的名称，以及包含该 IP 的配置是否已经生效
+    pub backends: Vec<(String, bool)>,
+    /// 开启了滑动续期，可以主动续期
+    pub renewable: bool,
 }
 
 pub struct WhiteListService {
-    sender: Option<mpsc::Sender<Message>>,
+    sender: Option<mpsc::UnboundedSender<Message>>,
 }
 
 impl WhiteListService {
     pub fn new(
         config: WhiteListServiceConfig,
+        backends: Vec<Box<dyn Backend>>,
         msgsvc: Option<MessageService>,
         locsvc: Option<BaiduLocationService>,
     ) -> Self {
-        let (s, r) = mpsc::channel::<Message>();
+        let (s, r) = mpsc::unbounded_channel::<Message>();
+        let preset_nets = parse_preset(&config.preset);
         let mut inner = WhiteListServiceImpl {
             config,
+            preset_nets,
             list: HashMap::new(),
             last_list: Vec::new(),
+            last_cidrs: Vec::new(),
+            logged_out: Vec::new(),
+            dirty: false,
+            refreshed: false,
+            backend_status: backends
+                .iter()
+                .map(|b| BackendStatus::new(b.name()))
+                .collect(),
+            backends: backends.into_iter().map(BackendSlot::new).collect(),
+            refreshing: None,
             receiver: r,
-            msgsvc,
-            locsvc,
+            msgsvc: msgsvc.map(Arc::new),
+            locsvc: locsvc.map(Arc::new),
+            ready: false,
         };
+        inner.load_state();
         tokio::spawn(async move {
-            // 启动时写出一个空配置
-            inner.on_list_changed(&[]);
+            // 启动时按恢复的列表写出配置
+            let curlist: Vec<IpNet> = inner.list.keys().cloned().collect();
+            inner.on_list_changed(&curlist);
+            inner.last_list = curlist;
             inner.run().await;
         });
         WhiteListService { sender: Some(s) }
     }
 
-    pub fn push(&mut self, ip: IpAddr) {
+    pub fn push(&mut self, ip: IpAddr, owner: &str, ttl: Duration) {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Push(ip, owner.to_string(), ttl))
+            .unwrap();
+    }
+
+    /// 立即删除不属于这些用户的记录，用于吊销用户
+    pub fn retain_owners(&mut self, owners: Vec<String>) {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::RetainOwners(owners))
+            .unwrap();
+    }
+
+    /// 当前白名单中的所有项，不含预置项，按添加时间排序
+    pub fn list(&self) -> impl Future<Output = Vec<EntryInfo>> {
+        let (s, r) = oneshot::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::List(s))
+            .unwrap();
+        async move { r.await.unwrap_or_default() }
+    }
+
+    /// 添加一项，`prefixlen` 为空时按 `ipv4_prefixlen`、`ipv6_prefixlen` 处理，和提交 token 相同，
+    /// `ttl` 为空时使用 `timeout`，前缀长度超出地址范围时返回 `None`
+    pub fn add(
+        &mut self,
+        addr: IpAddr,
+        prefixlen: Option<u8>,
+        ttl: Option<Duration>,
+        owner: &str,
+    ) -> impl Future<Output = Option<EntryInfo>> {
+        let (s, r) = oneshot::channel();
+        let req = AddRequest {
+            addr,
+            prefixlen,
+            ttl,
+            owner: owner.to_string(),
+        };
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Add(req, s))
+            .unwrap();
+        async move { r.await.ok().flatten() }
+    }
+
+    /// 删除与 `net` 相同的项，`net` 为单个地址时也删除以该地址提交、按前缀长度扩展的项，返回删除的数量
+    pub fn remove(&mut self, net: IpNet) -> impl Future<Output = usize> {
+        let (s, r) = oneshot::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Remove(net, s))
+            .unwrap();
+        async move { r.await.unwrap_or(0) }
+    }
+
+    /// 把匹配的项（规则同 `remove`）的剩余时间延长到 `ttl`，已经更长的不变，`ttl` 为空时使用 `timeout`，
+    /// 返回延长后的项
+    pub fn extend(
+        &mut self,
+        net: IpNet,
+        ttl: Option<Duration>,
+    ) -> impl Future<Output = Vec<EntryInfo>> {
+        let (s, r) = oneshot::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Extend(net, ttl, s))
+            .unwrap();
+        async move { r.await.unwrap_or_default() }
+    }
+
+    /// 注销：立即删除包含该 IP 的提交项（包括按前缀扩展的），`all` 为真时也删除通过接口添加的网段，
+    /// 返回被删除的项
+    pub fn logout(&mut self, ip: IpAddr, all: bool) -> impl Future<Output = Vec<EntryInfo>> {
+        let (s, r) = oneshot::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Logout(ip, all, s))
+            .unwrap();
+        async move { r.await.unwrap_or_default() }
+    }
+
+    /// 主动续期：把包含该 IP 的项的过期时间重新设为添加时的时长，只在开启了滑动续期时有效，
+    /// 返回续期后的项
+    pub fn renew(&mut self, ip: IpAddr) -> impl Future<Output = Vec<EntryInfo>> {
+        let (s, r) = oneshot::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Renew(ip, s))
+            .unwrap();
+        async move { r.await.unwrap_or_default() }
+    }
+
+    /// 查询 IP 的详细状态，包括是否已在各后端生效
+    pub fn status(&self, ip: IpAddr) -> impl Future<Output = IpStatus> {
+        let (s, r) = oneshot::channel();
         self.sender
             .as_ref()
             .unwrap()
-            .send(Message::Push(ip))
+            .send(Message::Status(ip, s))
             .unwrap();
+        async move { r.await.unwrap_or_default() }
     }
 
-    pub fn stop(&mut self) {
+    /// 查询 IP 当前是否在白名单中，包括前缀匹配和预置项
+    ///
+    /// 直接查询内存中的列表，新提交的 IP 无需等待下次刷新即可查到。
+    pub fn check(&self, ip: IpAddr) -> impl Future<Output = bool> {
+        let (s, r) = oneshot::channel();
         self.sender
-            .take()
+            .as_ref()
             .unwrap()
-            .send(Message::Terminate)
+            .send(Message::Check(ip, s))
             .unwrap();
+        async move { r.await.unwrap_or(false) }
+    }
+
+    /// 停止服务，返回的 future 在状态保存完成后结束
+    pub fn stop(&mut self) -> impl Future<Output = ()> {
+        let (s, r) = oneshot::channel();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Message::Terminate(Some(s)));
+        }
+        async move {
+            let _ = r.await;
+        }
     }
 }
 
 impl Drop for WhiteListService {
     fn drop(&mut self) {
         if let Some(sender) = self.sender.as_ref() {
-            sender.send(Message::Terminate).unwrap();
+            let _ = sender.send(Message::Terminate(None));
         }
     }
 }
 
 struct WhiteListServiceImpl {
     config: WhiteListServiceConfig,
-    list: HashMap<IpAddr, Instant>,
-    last_list: Vec<IpAddr>,
-    receiver: mpsc::Receiver<Message>,
-    msgsvc: Option<MessageService>,
-    locsvc: Option<BaiduLocationService>,
+    preset_nets: Vec<IpNet>,
+    /// 提交的 IP 以单个地址为键，通过接口添加的网段以该网段为键，同一地址的不同网段互不覆盖
+    list: HashMap<IpNet, Record>,
+    last_list: Vec<IpNet>,
+    /// 上一次刷新后端时的 CIDR 列表
+    last_cidrs: Vec<String>,
+    /// 上次刷新后注销的项，下次刷新时发送通知
+    logged_out: Vec<String>,
+    dirty: bool,
+    refreshed: bool,
+    /// 刷新在后台线程中进行，期间后端移交给刷新任务，这里为空
+    backends: Vec<BackendSlot>,
+    /// 各后端上次刷新完成后的状态，刷新期间也可以查询
+    backend_status: Vec<BackendStatus>,
+    /// 正在进行的刷新，完成后交还后端和需要通知的错误信息
+    refreshing: Option<JoinHandle<(Vec<BackendSlot>, Vec<String>)>>,
+    receiver: mpsc::UnboundedReceiver<Message>,
+    /// 通知在后台任务中发送，查询位置和发送消息都可能很慢，不能阻塞刷新和看门狗心跳
+    msgsvc: Option<Arc<MessageService>>,
+    locsvc: Option<Arc<BaiduLocationService>>,
+    /// 已通知 systemd 启动完成
+    ready: bool,
 }
 
 impl WhiteListServiceImpl {
     async fn run(&mut self) {
+        let mut interval = tokio::time::interval(self.config.loop_delay);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // 第一次 tick 立即返回，跳过
+        interval.tick().await;
+        // 看门狗心跳也在这里发送，处理消息卡住时 systemd 会重启服务
+        let watchdog_period = crate::systemd::watchdog_interval();
+        let mut watchdog =
+            tokio::time::interval(watchdog_period.unwrap_or(Duration::from_secs(3600)));
         loop {
-            tokio::time::sleep(self.config.loop_delay).await;
-            while let Ok(msg) = self.receiver.try_recv() {
-                match msg {
-                    Message::Terminate => return,
-                    Message::Push(ip) => self.push(ip),
+            // 消息立即处理，列表的刷新仍然按 loop_delay 批量进行
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(Message::Push(ip, owner, ttl)) => self.push(ip, owner, ttl),
+                    Some(Message::Check(ip, s)) => {
+                        self.touch(&ip);
+                        let _ = s.send(self.contains(&ip));
+                    }
+                    Some(Message::RetainOwners(owners)) => self.retain_owners(&owners),
+                    Some(Message::List(s)) => {
+                        let _ = s.send(self.list_entries());
+                    }
+                    Some(Message::Add(req, s)) => {
+                        let _ = s.send(self.add(req));
+                    }
+                    Some(Message::Remove(net, s)) => {
+                        let _ = s.send(self.remove(&net));
+                    }
+                    Some(Message::Extend(net, ttl, s)) => {
+                        let _ = s.send(self.extend(&net, ttl));
+                    }
+                    Some(Message::Logout(ip, all, s)) => {
+                        let _ = s.send(self.logout(&ip, all));
+                    }
+                    Some(Message::Status(ip, s)) => {
+                        self.touch(&ip);
+                        let _ = s.send(self.status(&ip));
+                    }
+                    Some(Message::Renew(ip, s)) => {
+                        let _ = s.send(self.renew(&ip, true));
+                    }
+                    Some(Message::Terminate(s)) => {
+                        self.save_state();
+                        if let Some(s) = s {
+                            let _ = s.send(());
+                        }
+                        return;
+                    }
+                    None => {
+                        self.save_state();
+                        return;
+                    }
+                },
+                _ = interval.tick() => self.on_timer(),
+                result = async { self.refreshing.as_mut().unwrap().await }, if self.refreshing.is_some() => {
+                    self.refreshing = None;
+                    self.on_refreshed(result);
+                }
+                _ = watchdog.tick(), if watchdog_period.is_some() => {
+                    crate::systemd::notify_watchdog();
                 }
             }
-            self.on_timer().await;
         }
     }
 
-    fn push(&mut self, ip: IpAddr) {
-        self.list.insert(ip, Instant::now() + self.config.timeout);
+    fn push(&mut self, ip: IpAddr, owner: String, ttl: Duration) {
+        self.add(AddRequest {
+            addr: ip,
+            prefixlen: None,
+            ttl: Some(ttl),
+            owner,
+        });
     }
 
-    async fn on_timer(&mut self) {
-        self.list.retain(|_, t| &Instant::now() < t);
-        let curlist: Vec<IpAddr> = self.list.keys().cloned().collect();
-        let newip: Vec<IpAddr> = curlist
+    fn add(&mut self, req: AddRequest) -> Option<EntryInfo> {
+        let ip = req.addr.to_canonical();
+        // 0 在配置中表示不扩展，不能作为接口指定的前缀长度
+        let key = record_key(ip, req.prefixlen).filter(|_| req.prefixlen != Some(0));
+        let Some(key) = key else {
+            warn!("前缀长度无效: {}/{}", ip, req.prefixlen.unwrap_or_default());
+            return None;
+        };
+        let now = SystemTime::now();
+        let ttl = req.ttl.unwrap_or(self.config.timeout);
+        let record = Record {
+            expire: now + ttl,
+            ttl,
+            owner: req.owner,
+            created: self.list.get(&key).map(|r| r.created).unwrap_or(now),
+            prefixlen: req.prefixlen,
+        };
+        let info = self.entry_info(&key, &record);
+        if self.list.insert(key, record).is_some() {
+            // 已存在的 IP 只是延长了过期时间，也需要通知后端
+            self.refreshed = true;
+        }
+        self.dirty = true;
+        Some(info)
+    }
+
+    fn list_entries(&self) -> Vec<EntryInfo> {
+        let now = SystemTime::now();
+        let mut entries: Vec<EntryInfo> = self
+            .list
+            .iter()
+            .filter(|(_, r)| now < r.expire)
+            .map(|(ip, r)| self.entry_info(ip, r))
+            .collect();
+        entries.sort_by_key(|e| e.created);
+        entries
+    }
+
+    /// 记录是否与接口指定的 `net` 对应
+    fn matches(&self, net: &IpNet, key: &IpNet, record: &Record) -> bool {
+        self.record_net(key, record) == *net
+            || (record.prefixlen.is_none()
+                && net.prefix_len() == net.max_prefix_len()
+                && key.addr() == net.addr())
+    }
+
+    fn remove(&mut self, net: &IpNet) -> usize {
+        let targets: Vec<IpNet> = self
+            .list
+            .iter()
+            .filter(|(ip, r)| self.matches(net, ip, r))
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in &targets {
+            if let Some(r) = self.list.remove(ip) {
+                info!("删除 IP: {} ({})", self.record_cidr(ip, &r), r.owner);
+            }
+        }
+        if !targets.is_empty() {
+            self.dirty = true;
+        }
+        targets.len()
+    }
+
+    fn extend(&mut self, net: &IpNet, ttl: Option<Duration>) -> Vec<EntryInfo> {
+        let now = SystemTime::now();
+        let ttl = ttl.unwrap_or(self.config.timeout);
+        let targets: Vec<IpNet> = self
+            .list
+            .iter()
+            .filter(|(ip, r)| now < r.expire && self.matches(net, ip, r))
+            .map(|(ip, _)| *ip)
+            .collect();
+        let mut result = vec![];
+        for ip in &targets {
+            if let Some(r) = self.list.get_mut(ip) {
+                r.expire = r.expire.max(now + ttl);
+            }
+            result.push(self.entry_info(ip, &self.list[ip]));
+        }
+        if !targets.is_empty() {
+            self.refreshed = true;
+            self.dirty = true;
+        }
+        result
+    }
+
+    fn logout(&mut self, ip: &IpAddr, all: bool) -> Vec<EntryInfo> {
+        let ip = ip.to_canonical();
+        let now = SystemTime::now();
+        let targets: Vec<IpNet> = self
+            .list
+            .iter()
+            .filter(|(i, r)| {
+                now < r.expire
+                    && (all || r.prefixlen.is_none())
+                    && self.record_net(i, r).contains(&ip)
+            })
+            .map(|(i, _)| *i)
+            .collect();
+        let mut result = vec![];
+        for i in &targets {
+            if let Some(r) = self.list.remove(i) {
+                let info = self.entry_info(i, &r);
+                info!("{} 注销了 IP: {} ({})", ip, info.cidr, info.owner);
+                self.logged_out
+                    .push(format!("{}: {}", info.owner, info.cidr));
+                result.push(info);
+            }
+        }
+        if !targets.is_empty() {
+            self.dirty = true;
+        }
+        result
+    }
+
+    fn entry_info(&self, key: &IpNet, record: &Record) -> EntryInfo {
+        EntryInfo {
+            address: key.addr(),
+            cidr: self.record_cidr(key, record),
+            owner: record.owner.clone(),
+            created: record.created,
+            expire: record.expire,
+        }
+    }
+
+    fn retain_owners(&mut self, owners: &[String]) {
+        let count = self.list.len();
+        self.list.retain(|ip, r| {
+            let keep = owners.contains(&r.owner);
+            if !keep {
+                info!("用户 {} 已被吊销，删除 IP: {}", r.owner, key_to_string(ip));
+            }
+            keep
+        });
+        if self.list.len() != count {
+            self.dirty = true;
+        }
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.preset_nets.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        let now = SystemTime::now();
+        self.list
+            .iter()
+            .any(|(i, r)| now < r.expire && self.record_net(i, r).contains(&ip))
+    }
+
+    fn status(&self, ip: &IpAddr) -> IpStatus {
+        let ip = ip.to_canonical();
+        let now = SystemTime::now();
+        let preset = self.preset_nets.iter().any(|net| net.contains(&ip));
+        let mut entries: Vec<EntryInfo> = self
+            .list
+            .iter()
+            .filter(|(i, r)| now < r.expire && self.record_net(i, r).contains(&ip))
+            .map(|(i, r)| self.entry_info(i, r))
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.expire));
+        let backends = self
+            .backend_status
+            .iter()
+            .map(|b| {
+                let applied = (preset && b.applied)
+                    || entries.iter().any(|e| b.applied_cidrs.contains(&e.cidr));
+                (b.name.clone(), applied)
+            })
+            .collect();
+        IpStatus {
+            renewable: self.config.sliding && !entries.is_empty(),
+            preset,
+            entries,
+            backends,
+        }
+    }
+
+    /// 滑动续期：已经过了一半时长的项才续期，避免频繁刷新后端
+    fn touch(&mut self, ip: &IpAddr) {
+        if self.config.sliding {
+            self.renew(ip, false);
+        }
+    }
+
+    fn renew(&mut self, ip: &IpAddr, force: bool) -> Vec<EntryInfo> {
+        if !self.config.sliding {
+            return vec![];
+        }
+        let ip = ip.to_canonical();
+        let now = SystemTime::now();
+        let targets: Vec<IpNet> = self
+            .list
+            .iter()
+            .filter(|(i, r)| {
+                now < r.expire
+                    && self.record_net(i, r).contains(&ip)
+                    && (force || now + r.ttl / 2 > r.expire)
+            })
+            .map(|(i, _)| *i)
+            .collect();
+        let mut result = vec![];
+        for i in &targets {
+            if let Some(r) = self.list.get_mut(i) {
+                r.expire = r.expire.max(now + r.ttl);
+            }
+            let info = self.entry_info(i, &self.list[i]);
+            debug!("续期 IP: {} ({})", info.cidr, info.owner);
+            result.push(info);
+        }
+        if !targets.is_empty() {
+            // nftables 等后端的配置中带有过期时间，需要刷新
+            self.refreshed = true;
+            self.dirty = true;
+        }
+        result
+    }
+
+    fn on_timer(&mut self) {
+        let now = SystemTime::now();
+        let count = self.list.len();
+        self.list.retain(|_, r| now < r.expire);
+        if self.list.len() != count {
+            self.dirty = true;
+        }
+        if self.dirty {
+            self.save_state();
+        }
+        let logged_out = std::mem::take(&mut self.logged_out);
+        if !logged_out.is_empty() {
+            self.notify(format!("注销 {}", logged_out.join("; ")));
+        }
+        // 上一次刷新还没有完成，变化留到下次处理
+        if self.refreshing.is_some() {
+            return;
+        }
+        let curlist: Vec<IpNet> = self.list.keys().cloned().collect();
+        let newip: Vec<IpNet> = curlist
             .iter()
             .filter(|ip| !self.last_list.contains(ip))
             .cloned()
             .collect();
-        let delip: Vec<IpAddr> = self
+        let delip: Vec<IpNet> = self
             .last_list
             .iter()
             .filter(|ip| !curlist.contains(ip))
             .cloned()
             .collect();
-        if !newip.is_empty() || !delip.is_empty() {
+        // 上次刷新失败的后端每次都重试，不需要等到列表变化
+        let retry = self.backend_status.iter().any(|b| b.failed);
+        if !newip.is_empty() || !delip.is_empty() || self.refreshed || retry {
             if !newip.is_empty() {
-                let mut iplist = ipvec_to_strvec(&newip);
-                debug!("新增 IP: \n\t{}", iplist.join("\n\t"));
-                if let Some(msgsvc) = &self.msgsvc {
-                    if let Some(locsvc) = &self.locsvc {
-                        for (i, ip) in newip.iter().enumerate() {
-                            let mut ipstr = ip.to_string();
-                            match locsvc.get(ip).await {
-                                Ok(loc) => ipstr = format!("{}({})", ipstr, loc),
-                                Err(err) => error!("获取 {} 的位置失败: {}", ipstr, err),
-                            };
-                            iplist[i] = ipstr;
+                let owners: Vec<&str> = newip
+                    .iter()
+                    .map(|ip| self.list[ip].owner.as_str())
+                    .collect();
+                debug!(
+                    "新增 IP: \n\t{}",
+                    newip
+                        .iter()
+                        .zip(&owners)
+                        .map(|(ip, owner)| format!("{} ({})", key_to_string(ip), owner))
+                        .collect::<Vec<String>>()
+                        .join("\n\t")
+                );
+                if let Some(msgsvc) = self.msgsvc.clone() {
+                    let locsvc = self.locsvc.clone();
+                    let mut iplist = netvec_to_strvec(&newip);
+                    let addrs: Vec<IpAddr> = newip.iter().map(|ip| ip.addr()).collect();
+                    let owners: Vec<String> = owners.iter().map(|o| o.to_string()).collect();
+                    spawn_notification(async move {
+                        if let Some(locsvc) = &locsvc {
+                            for (ipstr, ip) in iplist.iter_mut().zip(&addrs) {
+                                match locsvc.get(ip).await {
+                                    Ok(loc) => *ipstr = format!("{}({})", ipstr, loc),
+                                    Err(err) => error!("获取 {} 的位置失败: {}", ipstr, err),
+                                };
+                            }
                         }
-                    }
-                    if let Err(err) = msgsvc.send(&iplist.join("; ")).await {
-                        error!("发送消息失败: {}", err);
-                    }
+                        let iplist: Vec<String> = iplist
+                            .iter()
+                            .zip(&owners)
+                            .map(|(ip, owner)| format!("{}: {}", owner, ip))
+                            .collect();
+                        if let Err(err) = msgsvc.send(&iplist.join("; ")).await {
+                            error!("发送消息失败: {}", err);
+                        }
+                    });
                 }
             }
             if !delip.is_empty() {
@@ -144,99 +664,431 @@ impl WhiteListServiceImpl {
                     "删除 IP: \n\t{}",
                     delip
                         .iter()
-                        .map(|ip| ip.to_string())
+                        .map(key_to_string)
                         .collect::<Vec<String>>()
                         .join("\n\t")
                 )
             }
             self.on_list_changed(&curlist);
             self.last_list = curlist;
+            self.refreshed = false;
+        }
+    }
+
+    /// 所有后端第一次刷新成功后通知 systemd 启动完成
+    ///
+    /// 启动时刷新失败的，systemd 会在启动超时后重启服务。
+    fn set_ready(&mut self, ok: bool) {
+        if self.ready {
+            return;
+        }
+        if ok {
+            crate::systemd::notify_ready();
+            self.ready = true;
+        } else {
+            warn!("刷新后端失败，暂不通知 systemd 启动完成");
         }
     }
 
-    fn on_list_changed(&self, list: &[IpAddr]) {
-        let list = self.ipvec_with_prefix(list);
-        if !list.is_empty() {
-            info!("当前列表:\n\t{}", list.join("\n\t"));
+    /// 在后台线程中刷新所有后端
+    ///
+    /// 刷新要运行外部命令、访问网络，可能需要很久，不能阻塞对 `check` 等请求的处理。
+    fn on_list_changed(&mut self, list: &[IpNet]) {
+        let entries = self.entries(list);
+        if !entries.is_empty() {
+            info!(
+                "当前列表:\n\t{}",
+                entries
+                    .iter()
+                    .map(|e| format!("{} ({})", e.cidr, e.owner))
+                    .collect::<Vec<String>>()
+                    .join("\n\t")
+            );
         } else {
             info!("当前列表: 【空】");
         }
 
-        let mut s = String::new();
-        s.push_str(&format!(
-            "geo ${} ${} {{\n",
-            self.config.remote_addr_var, self.config.result_var
-        ));
-        s.push_str("default 0;\n");
-        for i in &self.config.preset {
-            s.push_str(&format!("{} 1;\n", i));
+        if self.backends.is_empty() {
+            self.set_ready(true);
+            return;
         }
-        for i in list {
-            s.push_str(&format!("{} 1;\n", i));
+        let cidrs: Vec<String> = entries.iter().map(|e| e.cidr.clone()).collect();
+        let last_cidrs = std::mem::replace(&mut self.last_cidrs, cidrs.clone());
+        let added: Vec<String> = cidrs
+            .iter()
+            .filter(|c| !last_cidrs.contains(c))
+            .cloned()
+            .collect();
+        let mut removed: Vec<String> = Vec::new();
+        for c in last_cidrs {
+            if !cidrs.contains(&c) && !removed.contains(&c) {
+                removed.push(c);
+            }
         }
-        s.push_str("}\n");
-        debug!("写出配置:\n{}", s);
+        let preset = self.config.preset.clone();
+        let mut backends = std::mem::take(&mut self.backends);
+        self.refreshing = Some(tokio::task::spawn_blocking(move || {
+            let snapshot = Snapshot {
+                preset: &preset,
+                entries: &entries,
+                added: &added,
+                removed: &removed,
+            };
+            let mut errors = vec![];
+            for slot in backends.iter_mut() {
+                let error = slot.apply(&snapshot);
+                // 重试时同样的错误只通知一次
+                if error.is_some() && error != slot.last_error {
+                    errors.extend(error.clone());
+                }
+                slot.last_error = error;
+            }
+            (backends, errors)
+        }));
+    }
 
-        if let Err(err) = std::fs::write(&self.config.nginx_conf, s) {
-            error!("写出配置文件失败: {}", err);
-            return;
+    /// 刷新完成，收回后端并更新状态
+    fn on_refreshed(
+        &mut self,
+        result: std::result::Result<(Vec<BackendSlot>, Vec<String>), JoinError>,
+    ) {
+        let (backends, errors) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                error!("刷新后端的任务异常退出: {}", err);
+                return;
+            }
+        };
+        self.backends = backends;
+        self.backend_status = self.backends.iter().map(BackendSlot::status).collect();
+        if !errors.is_empty() {
+            self.notify(errors.join("; "));
         }
+        self.set_ready(self.backends.iter().all(|slot| !slot.failed));
+    }
 
-        let cwd = std::path::Path::new(&self.config.nginx_exe)
-            .parent()
-            .unwrap();
-        let p = std::process::Command::new(&self.config.nginx_exe)
-            .arg("-t")
-            .current_dir(cwd)
-            .spawn();
-        let status = p.and_then(|mut p| p.wait());
-        if let Err(err) = status {
-            error!("创建进程失败: {}", err);
+    /// 在后台任务中发送通知
+    fn notify(&self, msg: String) {
+        if let Some(msgsvc) = self.msgsvc.clone() {
+            spawn_notification(async move {
+                if let Err(err) = msgsvc.send(&msg).await {
+                    error!("发送消息失败: {}", err);
+                }
+            });
+        }
+    }
+
+    fn load_state(&mut self) {
+        if self.config.state_file.is_empty() {
             return;
-        } else {
-            let status = status.unwrap();
-            if !status.success() {
-                error!("新的配置文件测试失败: {}", status);
+        }
+        let path = std::path::Path::new(&self.config.state_file);
+        if !path.is_file() {
+            return;
+        }
+        let state: State = match read_state(path) {
+            Ok(state) => state,
+            Err(err) => {
+                error!("读取状态文件失败: {}", err);
                 return;
             }
+        };
+        let now = SystemTime::now();
+        for entry in state.entries {
+            let expire = UNIX_EPOCH + Duration::from_secs(entry.expire);
+            if expire > now && self.config.owners.contains(&entry.owner) {
+                let Some(key) = record_key(entry.ip, entry.prefixlen) else {
+                    warn!("状态文件中的前缀长度无效: {}", entry.ip);
+                    continue;
+                };
+                self.list.insert(
+                    key,
+                    Record {
+                        expire,
+                        ttl: entry
+                            .ttl
+                            .map(Duration::from_secs)
+                            .unwrap_or(self.config.timeout),
+                        owner: entry.owner,
+                        created: UNIX_EPOCH + Duration::from_secs(entry.created),
+                        prefixlen: entry.prefixlen,
+                    },
+                );
+            }
         }
+        info!("已从状态文件恢复 {} 个 IP", self.list.len());
+    }
 
-        let p = std::process::Command::new(&self.config.nginx_exe)
-            .arg("-s")
-            .arg("reload")
-            .current_dir(cwd)
-            .spawn();
-        let status = p.and_then(|mut p| p.wait());
-        if let Err(err) = status {
-            error!("创建进程失败: {}", err);
+    fn save_state(&mut self) {
+        if self.config.state_file.is_empty() {
             return;
-        } else {
-            let status = status.unwrap();
-            if !status.success() {
-                error!("刷新配置失败: {}", status);
-                return;
+        }
+        let state = State {
+            entries: self
+                .list
+                .iter()
+                .map(|(key, r)| StateEntry {
+                    ip: key.addr(),
+                    expire: r
+                        .expire
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    owner: r.owner.clone(),
+                    created: r
+                        .created
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    prefixlen: r.prefixlen,
+                    ttl: Some(r.ttl.as_secs()),
+                })
+                .collect(),
+        };
+        if let Err(err) = write_state(std::path::Path::new(&self.config.state_file), &state) {
+            error!("写出状态文件失败: {}", err);
+            return;
+        }
+        self.dirty = false;
+    }
+
+    fn prefixlen(&self, key: &IpNet, record: &Record) -> u8 {
+        record.prefixlen.unwrap_or(match key {
+            IpNet::V4(_) => self.config.ipv4_prefixlen,
+            IpNet::V6(_) => self.config.ipv6_prefixlen,
+        })
+    }
+
+    fn record_cidr(&self, key: &IpNet, record: &Record) -> String {
+        let prefixlen = self.prefixlen(key, record);
+        match key.addr() {
+            IpAddr::V4(ip) => ipv4_to_cidr(&ip, prefixlen),
+            IpAddr::V6(ip) => ipv6_to_cidr(&ip, prefixlen),
+        }
+    }
+
+    fn record_net(&self, key: &IpNet, record: &Record) -> IpNet {
+        let prefixlen = match self.prefixlen(key, record) {
+            0 => key.max_prefix_len(),
+            n => n,
+        };
+        IpNet::new(key.addr(), prefixlen)
+            .map(|net| net.trunc())
+            .unwrap_or(*key)
+    }
+
+    /// 把 IP 列表转换为 CIDR 列表，同一网段只保留一项，取最晚的过期时间
+    fn entries(&self, list: &[IpNet]) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for key in list {
+            let Some(record) = self.list.get(key) else {
+                continue;
+            };
+            let cidr = self.record_cidr(key, record);
+            if let Some(e) = entries.iter_mut().find(|e| e.cidr == cidr) {
+                if record.expire > e.expire {
+                    e.expire = record.expire;
+                    e.owner = record.owner.clone();
+                }
+            } else {
+                entries.push(Entry {
+                    addr: key.addr(),
+                    cidr,
+                    expire: record.expire,
+                    owner: record.owner.clone(),
+                });
             }
         }
+        entries
+    }
+}
+
+struct Record {
+    expire: SystemTime,
+    /// 添加时请求的时长，滑动续期时使用
+    ttl: Duration,
+    /// 添加该记录的用户
+    owner: String,
+    created: SystemTime,
+    /// 通过接口添加的网段，为空时使用配置中的前缀长度
+    prefixlen: Option<u8>,
+}
+
+struct BackendSlot {
+    backend: Box<dyn Backend>,
+    /// 上次成功生效的配置内容
+    applied: Option<String>,
+    /// 上次成功生效的配置中包含的 CIDR
+    applied_cidrs: Vec<String>,
+    /// 上次刷新失败，后端的状态不确定，即使配置内容未变化也要重新生效，每次定时刷新时都会重试
+    failed: bool,
+    /// 上次刷新失败的错误信息
+    last_error: Option<String>,
+}
+
+/// 后端的生效状态，用于查询 IP 状态和判断是否需要重试
+struct BackendStatus {
+    name: String,
+    /// 已经成功生效过，预置项已生效
+    applied: bool,
+    applied_cidrs: Vec<String>,
+    failed: bool,
+}
 
-        info!("已刷新配置");
+impl BackendStatus {
+    fn new(name: &str) -> Self {
+        BackendStatus {
+            name: name.to_string(),
+            applied: false,
+            applied_cidrs: Vec::new(),
+            failed: false,
+        }
     }
+}
 
-    fn ipvec_with_prefix(&self, v: &[IpAddr]) -> Vec<String> {
-        v.iter()
-            .map(|ip| match ip {
-                IpAddr::V4(ip) => ipv4_to_cidr(ip, self.config.ipv4_prefixlen),
-                IpAddr::V6(ip) => ipv6_to_cidr(ip, self.config.ipv6_prefixlen),
-            })
-            .collect()
+impl BackendSlot {
+    fn new(backend: Box<dyn Backend>) -> Self {
+        BackendSlot {
+            backend,
+            applied: None,
+            applied_cidrs: Vec::new(),
+            failed: false,
+            last_error: None,
+        }
+    }
+
+    fn status(&self) -> BackendStatus {
+        BackendStatus {
+            name: self.backend.name().to_string(),
+            applied: self.applied.is_some(),
+            applied_cidrs: self.applied_cidrs.clone(),
+            failed: self.failed,
+        }
+    }
+
+    /// 刷新失败时返回错误信息，用于发送通知
+    fn apply(&mut self, snapshot: &Snapshot) -> Option<String> {
+        let backend = self.backend.as_mut();
+        let name = backend.name().to_string();
+        let content = match backend.render(snapshot) {
+            Ok(content) => content,
+            Err(err) => {
+                error!("[{}] 生成配置失败: {}", name, err);
+                self.failed = true;
+                return Some(format!("[{}] 生成配置失败: {}", name, err));
+            }
+        };
+        let cidrs = snapshot.entries.iter().map(|e| e.cidr.clone()).collect();
+        if !self.failed && self.applied.as_ref() == Some(&content) {
+            debug!("[{}] 配置未变化", name);
+            self.applied_cidrs = cidrs;
+            return None;
+        }
+        debug!("[{}] 写出配置:\n{}", name, content);
+        self.failed = true;
+        if let Err(err) = backend.apply(snapshot, &content) {
+            error!("[{}] 写出配置文件失败: {}", name, err);
+            return Some(format!("[{}] 写出配置文件失败: {}", name, err));
+        }
+        let result = backend
+            .verify()
+            .map_err(|err| format!("新的配置文件测试失败: {}", err))
+            .and_then(|_| {
+                backend
+                    .reload(snapshot)
+                    .map_err(|err| format!("刷新配置失败: {}", err))
+            });
+        if let Err(msg) = result {
+            error!("[{}] {}", name, msg);
+            let mut msg = format!("[{}] {}", name, msg);
+            match backend.rollback() {
+                Ok(true) => {
+                    warn!("[{}] 已恢复上一次的配置", name);
+                    msg.push_str("，已恢复上一次的配置");
+                }
+                Ok(false) => {}
+                Err(err) => error!("[{}] 恢复配置失败: {}", name, err),
+            }
+            return Some(msg);
+        }
+        self.applied = Some(content);
+        self.applied_cidrs = cidrs;
+        self.failed = false;
+        info!("[{}] 已刷新配置", name);
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    entries: Vec<StateEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateEntry {
+    ip: IpAddr,
+    /// 过期时间，unix 时间戳（秒）
+    expire: u64,
+    #[serde(default = "default_owner")]
+    owner: String,
+    /// 添加时间，unix 时间戳（秒），旧版状态文件为 0
+    #[serde(default)]
+    created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefixlen: Option<u8>,
+    /// 添加时请求的时长（秒），旧版状态文件没有，使用 `timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
+
+/// 旧版状态文件没有记录用户，视为旧版 token 对应的用户
+fn default_owner() -> String {
+    "default".into()
+}
+
+fn parse_preset(preset: &[String]) -> Vec<IpNet> {
+    let mut nets = vec![];
+    for i in preset {
+        if let Ok(net) = i.parse::<IpNet>() {
+            nets.push(net);
+        } else if let Ok(ip) = i.parse::<IpAddr>() {
+            nets.push(IpNet::from(ip));
+        } else {
+            warn!("无效的预置项: {}", i);
+        }
     }
+    nets
 }
 
-fn ipvec_to_strvec(v: &[IpAddr]) -> Vec<String> {
-    v.iter().map(|ip| ip.to_string()).collect()
+/// 记录的键，`prefixlen` 为空时为单个地址，超出地址范围时返回 `None`
+fn record_key(ip: IpAddr, prefixlen: Option<u8>) -> Option<IpNet> {
+    let max = IpNet::from(ip).max_prefix_len();
+    IpNet::new(ip, prefixlen.unwrap_or(max))
+        .ok()
+        .map(|net| net.trunc())
+}
+
+/// 单个地址不带前缀长度
+fn key_to_string(key: &IpNet) -> String {
+    if key.prefix_len() == key.max_prefix_len() {
+        key.addr().to_string()
+    } else {
+        key.to_string()
+    }
+}
+
+/// 查询位置和发送消息使用阻塞的 http 请求，放到阻塞线程池中执行，不占用异步工作线程
+fn spawn_notification(fut: impl Future<Output = ()> + Send + 'static) {
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || handle.block_on(fut));
+}
+
+fn netvec_to_strvec(v: &[IpNet]) -> Vec<String> {
+    v.iter().map(key_to_string).collect()
 }
 
 fn ipv4_to_cidr(ip: &Ipv4Addr, prefixlen: u8) -> String {
-    if prefixlen == 0 || prefixlen == 32 {
+    if prefixlen == 0 || prefixlen >= 32 {
         return ip.to_string();
     }
     let mut ipu32 = u32::from_be_bytes(ip.octets());
@@ -247,7 +1099,7 @@ fn ipv4_to_cidr(ip: &Ipv4Addr, prefixlen: u8) -> String {
 }
 
 fn ipv6_to_cidr(ip: &Ipv6Addr, prefixlen: u8) -> String {
-    if prefixlen == 0 || prefixlen == 128 {
+    if prefixlen == 0 || prefixlen >= 128 {
         return ip.to_string();
     }
     let mut ipu128 = u128::from_be_bytes(ip.octets());
@@ -256,3 +1108,244 @@ fn ipv6_to_cidr(ip: &Ipv6Addr, prefixlen: u8) -> String {
     let ip6 = Ipv6Addr::from(ipu128);
     format!("{}/{}", ip6, prefixlen)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+
+    fn service(config: WhiteListServiceConfig) -> WhiteListServiceImpl {
+        let (_, receiver) = mpsc::unbounded_channel();
+        WhiteListServiceImpl {
+            preset_nets: parse_preset(&config.preset),
+            config,
+            list: HashMap::new(),
+            last_list: Vec::new(),
+            last_cidrs: Vec::new(),
+            logged_out: Vec::new(),
+            dirty: false,
+            refreshed: false,
+            backends: Vec::new(),
+            backend_status: Vec::new(),
+            refreshing: None,
+            receiver,
+            msgsvc: None,
+            locsvc: None,
+            ready: false,
+        }
+    }
+
+    fn config() -> WhiteListServiceConfig {
+        WhiteListServiceConfig {
+            timeout: Duration::from_secs(3600),
+            loop_delay: Duration::from_secs(15),
+            ipv4_prefixlen: 24,
+            ipv6_prefixlen: 64,
+            preset: vec![],
+            state_file: String::new(),
+            owners: vec![],
+            sliding: false,
+        }
+    }
+
+    fn add(svc: &mut WhiteListServiceImpl, net: &str, prefixlen: Option<u8>) -> Option<EntryInfo> {
+        svc.add(AddRequest {
+            addr: net.parse().unwrap(),
+            prefixlen,
+            ttl: None,
+            owner: "ops".into(),
+        })
+    }
+
+    fn cidrs(svc: &WhiteListServiceImpl) -> Vec<String> {
+        let mut cidrs: Vec<String> = svc.list_entries().into_iter().map(|e| e.cidr).collect();
+        cidrs.sort();
+        cidrs
+    }
+
+    #[test]
+    fn networks_with_same_address_do_not_overwrite() {
+        let mut svc = service(config());
+        add(&mut svc, "10.0.0.0", Some(8)).unwrap();
+        add(&mut svc, "10.0.0.0", Some(16)).unwrap();
+        svc.push(
+            "10.0.0.0".parse().unwrap(),
+            "alice".into(),
+            Duration::from_secs(60),
+        );
+        assert_eq!(cidrs(&svc), ["10.0.0.0/16", "10.0.0.0/24", "10.0.0.0/8"]);
+
+        // 删除单个地址只删除按前缀扩展的提交项，不影响接口添加的网段
+        assert_eq!(svc.remove(&"10.0.0.0/32".parse().unwrap()), 1);
+        assert_eq!(svc.remove(&"10.0.0.0/8".parse().unwrap()), 1);
+        assert_eq!(cidrs(&svc), ["10.0.0.0/16"]);
+    }
+
+    #[test]
+    fn anonymous_logout_keeps_api_networks() {
+        let mut svc = service(config());
+        add(&mut svc, "127.0.0.0", Some(8)).unwrap();
+        svc.push(
+            "127.0.0.5".parse().unwrap(),
+            "alice".into(),
+            Duration::from_secs(60),
+        );
+        let ip = "127.0.0.1".parse().unwrap();
+        let removed = svc.logout(&ip, false);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].cidr, "127.0.0.0/24");
+        assert_eq!(cidrs(&svc), ["127.0.0.0/8"]);
+        assert!(svc.logout(&ip, false).is_empty());
+        assert_eq!(svc.logout(&ip, true).len(), 1);
+        assert!(svc.list.is_empty());
+    }
+
+    #[test]
+    fn add_rejects_invalid_prefix() {
+        let mut svc = service(config());
+        assert!(add(&mut svc, "0.0.0.0", Some(0)).is_none());
+        assert!(add(&mut svc, "1.2.3.0", Some(33)).is_none());
+        // IPv4 映射地址会被转换为 IPv4，IPv6 的前缀长度不再有效
+        assert!(add(&mut svc, "::ffff:1.2.3.0", Some(120)).is_none());
+        assert!(svc.list.is_empty());
+        let entry = add(&mut svc, "2001:db8::", Some(32)).unwrap();
+        assert_eq!(entry.cidr, "2001:db8::/32");
+    }
+
+    /// 前 `failures` 次生效失败的后端，记录调用 `reload` 的次数
+    struct FlakyBackend {
+        failures: usize,
+        reloads: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Backend for FlakyBackend {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn render(&self, snapshot: &Snapshot) -> Result<String> {
+            Ok(snapshot
+                .entries
+                .iter()
+                .map(|e| e.cidr.clone())
+                .collect::<Vec<String>>()
+                .join("\n"))
+        }
+
+        fn apply(&mut self, _snapshot: &Snapshot, _content: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+            let n = self
+                .reloads
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if n < self.failures {
+                return Err("reload failed".into());
+            }
+            Ok(())
+        }
+    }
+
+    /// 等到 `reload` 被调用时才继续
+    struct BlockingBackend(std::sync::mpsc::Receiver<()>);
+
+    impl Backend for BlockingBackend {
+        fn name(&self) -> &str {
+            "blocking"
+        }
+
+        fn render(&self, snapshot: &Snapshot) -> Result<String> {
+            Ok(format!("{}", snapshot.entries.len()))
+        }
+
+        fn apply(&mut self, _snapshot: &Snapshot, _content: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn reload(&mut self, _snapshot: &Snapshot) -> Result<()> {
+            self.0.recv()?;
+            Ok(())
+        }
+    }
+
+    /// 定时刷新，并等待后端刷新完成
+    async fn tick(svc: &mut WhiteListServiceImpl) {
+        svc.on_timer();
+        if let Some(refreshing) = svc.refreshing.take() {
+            svc.on_refreshed(refreshing.await);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_backend_is_retried_on_next_tick() {
+        let mut svc = service(config());
+        let reloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let backend = FlakyBackend {
+            failures: 1,
+            reloads: reloads.clone(),
+        };
+        svc.backend_status.push(BackendStatus::new(backend.name()));
+        svc.backends.push(BackendSlot::new(Box::new(backend)));
+        let count = || reloads.load(std::sync::atomic::Ordering::SeqCst);
+        add(&mut svc, "10.0.0.1", None).unwrap();
+
+        tick(&mut svc).await;
+        assert_eq!(count(), 1);
+        assert!(svc.backend_status[0].failed);
+        assert!(!svc.ready);
+
+        // 列表没有变化，仍然重试失败的后端
+        tick(&mut svc).await;
+        assert_eq!(count(), 2);
+        assert!(!svc.backend_status[0].failed);
+        assert_eq!(svc.backend_status[0].applied_cidrs, ["10.0.0.0/24"]);
+        assert!(svc.ready);
+
+        // 成功后没有变化时不再刷新
+        tick(&mut svc).await;
+        assert_eq!(count(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_does_not_wait_for_refresh() {
+        let mut svc = service(config());
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        svc.backends
+            .push(BackendSlot::new(Box::new(BlockingBackend(rx))));
+        svc.backend_status.push(BackendStatus::new("blocking"));
+        add(&mut svc, "10.0.0.1", None).unwrap();
+        svc.on_timer();
+        assert!(svc.refreshing.is_some());
+
+        // 刷新期间仍然可以查询和修改列表，状态为上一次刷新的结果
+        add(&mut svc, "10.0.1.1", None).unwrap();
+        assert!(svc.contains(&"10.0.1.2".parse().unwrap()));
+        let status = svc.status(&"10.0.0.1".parse().unwrap());
+        assert_eq!(status.backends, [("blocking".to_string(), false)]);
+        // 刷新没有完成时不会开始新的刷新
+        svc.on_timer();
+
+        tx.send(()).unwrap();
+        let result = svc.refreshing.take().unwrap().await;
+        svc.on_refreshed(result);
+        let status = svc.status(&"10.0.0.1".parse().unwrap());
+        assert_eq!(status.backends, [("blocking".to_string(), true)]);
+        // 刷新期间的变化在下次刷新时处理
+        assert_eq!(svc.last_list.len(), 1);
+        tx.send(()).unwrap();
+        tick(&mut svc).await;
+        assert_eq!(svc.last_list.len(), 2);
+    }
+
+    #[test]
+    fn cidr_ignores_out_of_range_prefix() {
+        let ip4: Ipv4Addr = "1.2.3.4".parse().unwrap();
+        assert_eq!(ipv4_to_cidr(&ip4, 24), "1.2.3.0/24");
+        assert_eq!(ipv4_to_cidr(&ip4, 0), "1.2.3.4");
+        assert_eq!(ipv4_to_cidr(&ip4, 120), "1.2.3.4");
+        let ip6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(ipv6_to_cidr(&ip6, 64), "2001:db8::/64");
+        assert_eq!(ipv6_to_cidr(&ip6, 200), "2001:db8::1");
+    }
+}
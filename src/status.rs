@@ -0,0 +1,189 @@
+//! 状态页面：当前 IP 是否在白名单中、是否已经生效、还剩多久
+//!
+//! `GET {path}?status` 返回 html，请求头 `Accept` 包含 `application/json` 时返回 json。
+
+use crate::{api::unix_secs, service::IpStatus, MyAppData, Result};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use std::{fmt::Write, net::IpAddr, time::SystemTime};
+
+#[derive(Serialize)]
+struct StatusJson {
+    ip: IpAddr,
+    /// 在有效项或预置项中
+    whitelisted: bool,
+    preset: bool,
+    /// 所有后端都已生效
+    applied: bool,
+    /// 最晚的过期时间，unix 时间戳（秒），只在预置项中或不在白名单中时为空
+    expires: Option<u64>,
+    /// 剩余秒数
+    ttl: Option<u64>,
+    entries: Vec<EntryJson>,
+    backends: Vec<BackendJson>,
+    /// 可以主动续期
+    renewable: bool,
+}
+
+#[derive(Serialize)]
+struct EntryJson {
+    cidr: String,
+    expires: u64,
+    ttl: u64,
+}
+
+#[derive(Serialize)]
+struct BackendJson {
+    name: String,
+    applied: bool,
+}
+
+impl StatusJson {
+    fn new(ip: IpAddr, status: IpStatus) -> Self {
+        let now = SystemTime::now();
+        let ttl = |t: SystemTime| t.duration_since(now).unwrap_or_default().as_secs();
+        let expires = status.entries.iter().map(|e| e.expire).max();
+        StatusJson {
+            ip,
+            whitelisted: status.preset || !status.entries.is_empty(),
+            preset: status.preset,
+            applied: status.backends.iter().all(|(_, applied)| *applied),
+            expires: expires.map(unix_secs),
+            ttl: expires.map(ttl),
+            entries: status
+                .entries
+                .into_iter()
+                .map(|e| EntryJson {
+                    cidr: e.cidr,
+                    expires: unix_secs(e.expire),
+                    ttl: ttl(e.expire),
+                })
+                .collect(),
+            backends: status
+                .backends
+                .into_iter()
+                .map(|(name, applied)| BackendJson { name, applied })
+                .collect(),
+            renewable: status.renewable,
+        }
+    }
+}
+
+pub(crate) async fn handle(
+    ctx: &MyAppData,
+    req: &Request<Body>,
+    ip: IpAddr,
+) -> Result<Response<Body>> {
+    let want_json = req
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    if want_json {
+        return json(ctx, ip).await;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(page(ctx, ip).await.into())?)
+}
+
+pub(crate) async fn json(ctx: &MyAppData, ip: IpAddr) -> Result<Response<Body>> {
+    let status = ctx.service.lock().unwrap().status(ip);
+    let status = StatusJson::new(ip, status.await);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&status)?.into())?)
+}
+
+/// 状态页面，还没有生效时每 5 秒自动刷新
+pub(crate) async fn page(ctx: &MyAppData, ip: IpAddr) -> String {
+    let status = ctx.service.lock().unwrap().status(ip);
+    let status = StatusJson::new(ip, status.await);
+    let (state, refresh) = match (status.whitelisted, status.applied) {
+        (false, _) => ("不在白名单中", false),
+        (true, false) => ("已加入白名单，等待生效", true),
+        (true, true) => ("已生效", false),
+    };
+    let mut body = String::new();
+    let _ = writeln!(body, "    <p>当前 IP: {}</p>", status.ip);
+    let _ = writeln!(body, "    <p>状态: {}</p>", state);
+    if status.preset {
+        let _ = writeln!(body, "    <p>在预置的白名单中，长期有效</p>");
+    } else if let Some(ttl) = status.ttl {
+        let _ = writeln!(body, "    <p>剩余时间: {}</p>", format_secs(ttl));
+    }
+    if !status.entries.is_empty() {
+        let _ = writeln!(body, "    <ul>");
+        for e in &status.entries {
+            let _ = writeln!(
+                body,
+                "        <li>{}，剩余 {}</li>",
+                e.cidr,
+                format_secs(e.ttl)
+            );
+        }
+        let _ = writeln!(body, "    </ul>");
+    }
+    if status.whitelisted && status.backends.len() > 1 {
+        let _ = writeln!(body, "    <ul>");
+        for b in &status.backends {
+            let state = if b.applied {
+                "已生效"
+            } else {
+                "等待生效"
+            };
+            let _ = writeln!(body, "        <li>{}: {}</li>", b.name, state);
+        }
+        let _ = writeln!(body, "    </ul>");
+    }
+    if status.renewable {
+        let _ = writeln!(
+            body,
+            r#"    <form method="POST" action="?renew">
+        <button type="submit">续期</button>
+    </form>"#
+        );
+    }
+    if !status.entries.is_empty() {
+        let _ = writeln!(
+            body,
+            r#"    <form method="POST" action="?logout">
+        <button type="submit">注销</button>
+    </form>"#
+        );
+    }
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="zh">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <meta http-equiv="X-UA-Compatible" content="ie=edge">
+{}    <title>状态</title>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+        if refresh {
+            "    <meta http-equiv=\"refresh\" content=\"5; url=?status\">\n"
+        } else {
+            ""
+        },
+        body
+    )
+}
+
+/// 把秒数格式化为“1 小时 2 分 3 秒”
+fn format_secs(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{} 小时 {} 分 {} 秒", h, m, s)
+    } else if m > 0 {
+        format!("{} 分 {} 秒", m, s)
+    } else {
+        format!("{} 秒", s)
+    }
+}
@@ -0,0 +1,104 @@
+//! systemd 集成：socket 激活、启动完成通知和看门狗，不是由 systemd 启动时都不起作用
+
+#[cfg(unix)]
+use std::path::Path;
+use std::{net::SocketAddr, time::Duration};
+
+enum InheritedSocket {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// systemd 通过 `LISTEN_FDS` 传入的监听 socket，按地址取出
+pub struct InheritedSockets(Vec<InheritedSocket>);
+
+impl InheritedSockets {
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<std::net::TcpListener> {
+        let i = self.0.iter().position(
+            |i| matches!(i, InheritedSocket::Tcp(l) if l.local_addr().ok() == Some(addr)),
+        )?;
+        match self.0.remove(i) {
+            InheritedSocket::Tcp(l) => Some(l),
+            #[cfg(unix)]
+            _ => None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn take_unix(&mut self, path: &Path) -> Option<std::os::unix::net::UnixListener> {
+        let i = self.0.iter().position(|i| match i {
+            InheritedSocket::Unix(l) => l.local_addr().is_ok_and(|a| a.as_pathname() == Some(path)),
+            _ => false,
+        })?;
+        match self.0.remove(i) {
+            InheritedSocket::Unix(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub fn listen_fds() -> InheritedSockets {
+    #[cfg(unix)]
+    {
+        use std::os::fd::{FromRawFd, IntoRawFd};
+        let fds = match sd_notify::listen_fds() {
+            Ok(fds) => fds,
+            Err(err) => {
+                error!("读取 systemd 传入的 socket 失败: {}", err);
+                return InheritedSockets(vec![]);
+            }
+        };
+        InheritedSockets(
+            fds.map(|fd| {
+                // 先当作 unix socket 取地址，地址类型不对时就是 TCP
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                if listener.local_addr().is_ok() {
+                    InheritedSocket::Unix(listener)
+                } else {
+                    let fd = listener.into_raw_fd();
+                    InheritedSocket::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+                }
+            })
+            .collect(),
+        )
+    }
+    #[cfg(not(unix))]
+    InheritedSockets(vec![])
+}
+
+/// 通知 systemd 启动完成
+pub fn notify_ready() {
+    #[cfg(unix)]
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        error!("通知 systemd 失败: {}", err);
+    }
+}
+
+/// 开启了看门狗时，返回发送心跳的间隔
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+            // 按 systemd 的建议，在超时时间的一半内发送
+            return Some(Duration::from_micros(usec / 2));
+        }
+    }
+    None
+}
+
+pub fn notify_watchdog() {
+    #[cfg(unix)]
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+        error!("通知 systemd 失败: {}", err);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::Result;
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::rustls;
+use x509_parser::extensions::GeneralName;
+
+/// 保存一个 https 监听地址的证书配置，收到 SIGHUP 时可以重新读取证书
+pub struct TlsAcceptor {
+    cert: String,
+    key: String,
+    client_ca: String,
+    config: RwLock<Arc<rustls::ServerConfig>>,
+}
+
+impl TlsAcceptor {
+    /// `client_ca` 不为空时会请求客户端证书，客户端可以不提供，但提供的证书必须由其中的 CA 签发
+    pub fn new(cert: &str, key: &str, client_ca: &str) -> Result<Self> {
+        Ok(TlsAcceptor {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            client_ca: client_ca.to_string(),
+            config: RwLock::new(Arc::new(load_config(cert, key, client_ca)?)),
+        })
+    }
+
+    /// 重新读取证书和私钥，失败时继续使用原来的证书
+    pub fn reload(&self) -> Result<()> {
+        let config = load_config(&self.cert, &self.key, &self.client_ca)?;
+        *self.config.write().unwrap() = Arc::new(config);
+        info!("已重新加载证书: {}", self.cert);
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.config.read().unwrap().clone().into()
+    }
+}
+
+/// 客户端证书中可用于匹配用户的名称：subject 的 CN，以及 SAN 中的 DNS、email 和 URI
+pub fn peer_names(conn: &rustls::ServerConnection) -> Vec<String> {
+    let Some(cert) = conn.peer_certificates().and_then(|i| i.first()) else {
+        return vec![];
+    };
+    let cert = match x509_parser::parse_x509_certificate(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(err) => {
+            warn!("无法解析客户端证书: {}", err);
+            return vec![];
+        }
+    };
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|i| i.as_str().ok())
+        .map(|i| i.to_string())
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(i) | GeneralName::RFC822Name(i) | GeneralName::URI(i) => {
+                    names.push(i.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+fn load_config(cert: &str, key: &str, client_ca: &str) -> Result<rustls::ServerConfig> {
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = if client_ca.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        for ca in load_certs(client_ca)? {
+            roots.add(&ca)?;
+        }
+        builder.with_client_cert_verifier(
+            rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+        )
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
+    let certs: Vec<rustls::Certificate> =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+    if certs.is_empty() {
+        return Err(format!("{} 中没有证书", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("{} 中没有私钥", path).into())
+}
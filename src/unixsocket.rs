@@ -0,0 +1,63 @@
+use crate::Result;
+use std::{
+    ffi::CString,
+    fs::Permissions,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+use tokio::net::UnixListener;
+
+/// 监听 unix socket，`mode` 为八进制的权限，`owner` 为 `用户[:组]`，都可以为空
+pub fn bind(path: &Path, mode: &str, owner: &str) -> Result<UnixListener> {
+    // 删除上次运行遗留的 socket 文件，其他类型的文件不动
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if !mode.is_empty() {
+        let mode = u32::from_str_radix(mode, 8).map_err(|_| format!("mode 无效: {}", mode))?;
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    if !owner.is_empty() {
+        let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+        let uid = if user.is_empty() {
+            None
+        } else {
+            Some(lookup_user(user)?)
+        };
+        let gid = if group.is_empty() {
+            None
+        } else {
+            Some(lookup_group(group)?)
+        };
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    Ok(listener)
+}
+
+fn lookup_user(name: &str) -> Result<u32> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let cname = CString::new(name)?;
+    // 只在启动时调用，不存在并发
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(format!("用户不存在: {}", name).into());
+    }
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+fn lookup_group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(format!("组不存在: {}", name).into());
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}